@@ -1,4 +1,26 @@
 use crate::protocol::*;
+use crate::simulation::{self, Camera};
+mod interpolation;
+mod characters;
+mod chat;
+mod sky;
+mod fog;
+mod lighting;
+use crate::client::interpolation::SnapshotBuffer;
+use crate::client::chat::{ChatHistory, Prompt, PromptEvent};
+use crate::client::sky::Sky;
+use crate::client::lighting::LightMap;
+
+/// time between two position reports, which also are snapshot requests
+const POSITION_INTERVAL: Duration = Duration::from_millis(100);
+/// say hello again when the server has been quiet for this long, our address may have changed
+const SERVER_SILENCE: Duration = Duration::from_secs(5);
+/// nicknames are only shown above players closer than this
+const LABEL_DISTANCE: f32 = 8.0;
+/// how long chat lines stay on screen
+const CHAT_DURATION: Duration = Duration::from_secs(15);
+/// packets we cannot read before the welcome, after which the server is deemed incompatible
+const MAX_DECODE_ERRORS: u32 = 10;
 use std::io::Read;
 use crossterm::terminal;
 use image::imageops::FilterType;
@@ -13,7 +35,7 @@ use std::thread;
 use std::fs::File;
 use std::io::BufReader;
 use rodio::Source;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::io::Write;
 use crossbeam_channel::Sender;
@@ -35,8 +57,46 @@ fn rmcup() {
     flush_stdout();
 }
 
+fn send(packet_sender: &Sender<Packet>, server: std::net::SocketAddr, message: &ClientMessage) {
+    let message_ser = bincode::serialize(message).unwrap();
+    // only fails once the socket thread is gone, the server will time us out
+    let _ = packet_sender.send(Packet::reliable_unordered(server, message_ser));
+}
+
+/// Inputs must be applied by the server in the order they were made
+fn send_input(packet_sender: &Sender<Packet>, server: std::net::SocketAddr, tick: u32, command: InputCommand) {
+    let message_ser = bincode::serialize(&ClientMessage::MessageInput(tick, command)).unwrap();
+    let _ = packet_sender.send(Packet::reliable_ordered(server, message_ser, None));
+}
+
+fn quit(message: &str) -> ! {
+    rmcup();
+    let _screen = crossterm_input::RawScreen::disable_raw_mode();
+    println!("{}", message);
+    std::process::exit(1);
+}
 
-fn render_floor_ceiling(textures: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>, tex_width: u32, tex_height: u32, color_buff: &mut Vec<u32>, w: usize, h: usize, pos_x: f32, pos_y: f32, dir_x:f32, dir_y: f32, plane_x: f32, plane_y: f32) {
+
+/// Value of `grid` at cell (x, y), 0 outside of it
+fn cell_value(grid: &Vec<Vec<u8>>, x: i32, y: i32) -> usize {
+    if x >= 0 && y >= 0 && (x as usize) < grid.len() && (y as usize) < grid[x as usize].len() {
+        grid[x as usize][y as usize] as usize
+    }
+    else {
+        0
+    }
+}
+
+/// Color of texel `tex_i` of texture `tex_id`, black for a texture not loaded yet
+fn texel(textures: &Vec<Vec<u8>>, tex_id: usize, tex_i: usize) -> u32 {
+    match textures.get(tex_id) {
+        Some(texture) => texture[tex_i] as u32 | ((texture[tex_i + 1] as u32) << 8) | ((texture[tex_i + 2] as u32) << 16),
+        None => 0,
+    }
+}
+
+fn render_floor_ceiling(textures: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>, floor: &Vec<Vec<u8>>, ceiling: &Vec<Vec<u8>>, sky: Option<&Sky>, fog: &Fog, light_map: &LightMap, tex_width: u32, tex_height: u32, color_buff: &mut Vec<u32>, w: usize, h: usize, pos_x: f32, pos_y: f32, dir_x:f32, dir_y: f32, plane_x: f32, plane_y: f32) {
+    let sky_columns = sky.map(|sky| sky.columns(w, dir_x, dir_y, plane_x, plane_y));
     for y in 0..h
     {
       // ray_dir for leftmost ray (x = 0) and rightmost ray (x = w)
@@ -56,6 +116,7 @@ fn render_floor_ceiling(textures: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>, tex
       // Horizontal distance from the camera to the floor for the current row.
       // 0.5 is the z position exactly in the middle between floor and ceiling.
       let row_distance = pos_z / p;
+      let visibility = fog::visibility(fog, row_distance);
 
       // calculate the real world step vector we have to add for each x (parallel to camera plane)
       // adding step by step avoids multiplications with a weight in the inner loop
@@ -79,49 +140,42 @@ fn render_floor_ceiling(textures: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>, tex
         floor_x += floor_step_x;
         floor_y += floor_step_y;
 
-        // choose texture and draw the pixel
-        let floor_texture = 3;
-        let ceiling_texture = 6;
-
-        let tex_id = floor_texture as usize;
+        // choose texture and draw the pixel, cells hold texture + 1 and 0 for none
         let tex_i = tx + ty * tex_width;
         let tex_i = (tex_i * 3) as usize;
 
-        let tex_id_layer = if (cell_x as usize) < world_layer.len() && (cell_y as usize) < world_layer[cell_x as usize].len() {
-            world_layer[cell_x as usize][cell_y as usize] as usize
-        }
-        else {
-            0
-        };
-
+        let tex_id_layer = cell_value(world_layer, cell_x, cell_y);
         let mut color = if tex_id_layer != 0 {
-            let tex_id_layer = tex_id_layer - 1;
-            textures[tex_id_layer][tex_i] as u32 |
-                ((textures[tex_id_layer][tex_i + 1] as u32) << 8) |
-                ((textures[tex_id_layer][tex_i + 2] as u32) << 16)
+            texel(textures, tex_id_layer - 1, tex_i)
         } else {
             0
         };
-        if color == 0 {
-            color = textures[tex_id][tex_i] as u32 |
-                ((textures[tex_id][tex_i + 1] as u32) << 8) |
-                ((textures[tex_id][tex_i + 2] as u32) << 16);
+        let tex_id = cell_value(floor, cell_x, cell_y);
+        if color == 0 && tex_id != 0 {
+            color = texel(textures, tex_id - 1, tex_i);
             color = (color >> 1) & 8355711
         };
-        color_buff[y as usize * w + x as usize] = color as u32;
+        let light = light_map.at(cell_x, cell_y);
+        color_buff[y as usize * w + x as usize] = fog::apply(fog, lighting::apply(color, light), visibility);
 
         //ceiling (symmetrical, at screen_height - y - 1 instead of y)
-        let tex_id = ceiling_texture as usize;
-        let color = textures[tex_id][tex_i] as u32 |
-                        ((textures[tex_id][tex_i + 1] as u32) << 8) |
-                        ((textures[tex_id][tex_i + 2] as u32) << 16);
-        let color = (color >> 1) & 8355711; // make a bit darker
+        let tex_id = cell_value(ceiling, cell_x, cell_y);
+        let color = if tex_id != 0 {
+            fog::apply(fog, lighting::apply((texel(textures, tex_id - 1, tex_i) >> 1) & 8355711, light), visibility) // make a bit darker
+        }
+        else {
+            // open to the sky
+            match (sky, &sky_columns) {
+                (Some(sky), Some(columns)) => sky.color(columns[x], h - y - 1, h),
+                _ => 0,
+            }
+        };
         color_buff[(h - y - 1) as usize * w + x as usize] = color as u32;
       }
     }
 }
 
-fn render_sprites(all_sprites_and_textures: &Vec<(&Vec<Vec<f32>>, &Vec<Vec<u8>>, u32, u32, bool, bool)>, color_buff: &mut Vec<u32>, depth_buff: &Vec<f32>, w: usize, h: usize, pos_x: f32, pos_y: f32, dir_x: f32, dir_y: f32, plane_x: f32, plane_y: f32, t: i32) -> Option<usize> {
+fn render_sprites(all_sprites_and_textures: &Vec<(&Vec<Vec<f32>>, &Vec<Vec<u8>>, u32, u32, bool, bool)>, fog: &Fog, light_map: &LightMap, color_buff: &mut Vec<u32>, depth_buff: &Vec<f32>, w: usize, h: usize, pos_x: f32, pos_y: f32, dir_x: f32, dir_y: f32, plane_x: f32, plane_y: f32, t: i32) -> Option<usize> {
     let mut matching_portal_index = None;
     // for sprites_and_textures in all_sprites_and_textures {
         // let (sprites, textures, texture_width, texture_height, rgba, portal_mapping) =  *sprites_and_textures;
@@ -164,6 +218,9 @@ fn render_sprites(all_sprites_and_textures: &Vec<(&Vec<Vec<f32>>, &Vec<Vec<u8>>,
             let transform_y = inv_det * (-plane_y * sprite_x + plane_x * sprite_y); //this is actually the depth inside the screen, that what Z is in 3_d
 
             let sprite_screen_x = ((w as f32 / 2.0) * (1.0 + transform_x / transform_y)) as i32;
+            // portals show a view which has its own fog and light
+            let visibility = if portal_mapping { 1.0 } else { fog::visibility(fog, transform_y) };
+            let light = if portal_mapping { [1.0; 3] } else { light_map.at(sprite[0] as i32, sprite[1] as i32) };
 
             //calculate height of the sprite on screen
             let sprite_height = ((h as f32 / (transform_y)) as i32).abs(); //using 'transform_y' instead of the real distance prevents fisheye
@@ -222,21 +279,20 @@ fn render_sprites(all_sprites_and_textures: &Vec<(&Vec<Vec<f32>>, &Vec<Vec<u8>>,
                             };
                             let tex_i = (tex_i * bytes_per_pixel) as usize;
                             let tex_id = sprite[2] as usize;
-                            let color = textures[tex_id][tex_i] as u32 |
-                                ((textures[tex_id][tex_i + 1] as u32) << 8) |
-                                ((textures[tex_id][tex_i + 2] as u32) << 16);
+                            let color = texel(textures, tex_id, tex_i);
                             if rgba {
-                                let alpha = textures[tex_id][tex_i + 3] as u32;
+                                // a texture not loaded yet is see-through
+                                let alpha = textures.get(tex_id).map_or(0, |texture| texture[tex_i + 3] as u32);
                                 let tran = 0xff - alpha;
-                                let text = &textures[tex_id];
+                                let fogged = fog::apply(fog, lighting::apply(color, light), visibility);
                                 let cbi = y as usize * w + stripe as usize;
                                 color_buff[cbi] = 
-                                    ((text[tex_i] as u32 & alpha) | (color_buff[cbi] & tran))
-                                    + (((((text[tex_i + 1] as u32) & alpha) << 8)) | ((color_buff[cbi]) & (tran << 8)))
-                                    + (((((text[tex_i + 2] as u32) & alpha ) << 16))| ((color_buff[cbi]) & (tran << 16)));
+                                    ((fogged & 0xff & alpha) | (color_buff[cbi] & tran))
+                                    + ((((fogged >> 8 & 0xff) & alpha) << 8) | ((color_buff[cbi]) & (tran << 8)))
+                                    + ((((fogged >> 16 & 0xff) & alpha) << 16) | ((color_buff[cbi]) & (tran << 16)));
                             }
                             else if (color & 0x00_fFFFFF) != 0 {
-                                color_buff[y as usize * w + stripe as usize] = color as u32
+                                color_buff[y as usize * w + stripe as usize] = fog::apply(fog, lighting::apply(color, light), visibility)
                             }
                         }
                     }
@@ -249,7 +305,65 @@ fn render_sprites(all_sprites_and_textures: &Vec<(&Vec<Vec<f32>>, &Vec<Vec<u8>>,
         matching_portal_index
 }
 
-fn render_walls(textures: &Vec<Vec<u8>>, texture_width: u32, texture_height: u32, world_map: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>, color_buff: &mut Vec<u32>, depth_buff: &mut Vec<f32>, w: usize, h: usize, pos_x: f32, pos_y: f32, dir_x: f32, dir_y: f32, plane_x: f32, plane_y: f32, start_dist: f32) {
+/// Draws `label` at half size above the character standing at (x, y), if it is in sight
+fn render_label(label: &Vec<u32>, label_width: usize, label_height: usize, x: f32, y: f32, color_buff: &mut Vec<u32>, depth_buff: &Vec<f32>, w: usize, h: usize, pos_x: f32, pos_y: f32, dir_x: f32, dir_y: f32, plane_x: f32, plane_y: f32) {
+    let sprite_x = x - pos_x;
+    let sprite_y = y - pos_y;
+    // same projection as render_sprites
+    let inv_det = 1.0 / (plane_x * dir_y - dir_x * plane_y);
+    let transform_x = inv_det * (dir_y * sprite_x - dir_x * sprite_y);
+    let transform_y = inv_det * (-plane_y * sprite_x + plane_x * sprite_y);
+    if transform_y <= 0.0 || transform_y > LABEL_DISTANCE {
+        return;
+    }
+    let sprite_screen_x = ((w as f32 / 2.0) * (1.0 + transform_x / transform_y)) as i32;
+    if sprite_screen_x < 0 || sprite_screen_x >= w as i32 || transform_y >= depth_buff[sprite_screen_x as usize] {
+        return;
+    }
+    let sprite_height = ((h as f32 / transform_y) as i32).abs();
+    let left = sprite_screen_x - label_width as i32 / 4;
+    let top = h as i32 / 2 - sprite_height / 2 - label_height as i32 / 2;
+    blit_text(label, label_width, label_height, left, top, color_buff, w, h);
+}
+
+/// Draws text rendered by `generate_text` at half size and in white, its top left corner at (left, top)
+fn blit_text(text_buff: &Vec<u32>, text_width: usize, text_height: usize, left: i32, top: i32, color_buff: &mut Vec<u32>, w: usize, h: usize) {
+    for text_y in 0..text_height as i32 / 2 {
+        for text_x in 0..text_width as i32 / 2 {
+            let screen_x = left + text_x;
+            let screen_y = top + text_y;
+            if screen_x < 0 || screen_y < 0 || screen_x >= w as i32 || screen_y >= h as i32 {
+                continue;
+            }
+            let value = text_buff[(text_y * 2) as usize * text_width + (text_x * 2) as usize];
+            if value != 0 {
+                color_buff[screen_y as usize * w + screen_x as usize] = value | (value << 8) | (value << 16);
+            }
+        }
+    }
+}
+
+/// Darkens the screen behind the scoreboard lines and draws them
+fn render_scoreboard(lines: &Vec<(usize, Vec<u32>)>, line_height: usize, color_buff: &mut Vec<u32>, w: usize, h: usize) {
+    let left = 20;
+    let top = 30;
+    // the shaded box reaches 10 pixels past the text on each side
+    let right = (w + 10).saturating_sub(left);
+    if right <= left - 10 || h <= top {
+        return;
+    }
+    let bottom = (top + lines.len() * line_height / 2 + 10).min(h);
+    for y in top - 10..bottom {
+        for x in left - 10..right {
+            color_buff[y * w + x] = (color_buff[y * w + x] >> 2) & 0x3f3f3f;
+        }
+    }
+    for (i, (line_width, line)) in lines.iter().enumerate() {
+        blit_text(line, *line_width, line_height, left as i32, (top + i * line_height / 2) as i32, color_buff, w, h);
+    }
+}
+
+fn render_walls(textures: &Vec<Vec<u8>>, texture_width: u32, texture_height: u32, world_map: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>, doors: &Vec<Door>, fog: &Fog, light_map: &LightMap, color_buff: &mut Vec<u32>, depth_buff: &mut Vec<f32>, w: usize, h: usize, pos_x: f32, pos_y: f32, dir_x: f32, dir_y: f32, plane_x: f32, plane_y: f32, start_dist: f32) {
 
 
   for x in 0..w
@@ -299,6 +413,8 @@ fn render_walls(textures: &Vec<Vec<u8>>, texture_width: u32, texture_height: u32
           side_dist_y = (map_y  as f32+ 1.0 - pos_y as f32) * delta_dist_y;
       }
       let mut ray_out_of_map = false;
+      // distance to the door hit and where along it, from its edge
+      let mut door_hit = None;
       //perform DDA
       while hit == 0
       {
@@ -324,7 +440,21 @@ fn render_walls(textures: &Vec<Vec<u8>>, texture_width: u32, texture_height: u32
                   break;
               }
               if world_map[map_x as usize][map_y as usize] > 0 {
-                  hit = 1;
+                  match simulation::door_at(doors, map_x as usize, map_y as usize) {
+                      Some(door) => {
+                          // the door stands across the middle of its cell, the ray goes on through the gap it leaves
+                          let faces_x = simulation::door_faces_x(world_map, door);
+                          let distance = if faces_x { (map_x as f32 + 0.5 - pos_x) / ray_dir_x } else { (map_y as f32 + 0.5 - pos_y) / ray_dir_y };
+                          let along = if faces_x { pos_y + distance * ray_dir_y } else { pos_x + distance * ray_dir_x };
+                          let cell_along = if faces_x { map_y } else { map_x };
+                          if along.floor() as i32 == cell_along && along - along.floor() >= door.open {
+                              hit = 1;
+                              side = if faces_x { 0 } else { 1 };
+                              door_hit = Some((distance, along - along.floor() - door.open));
+                          }
+                      },
+                      None => hit = 1,
+                  }
               }
           }
       }
@@ -332,13 +462,28 @@ fn render_walls(textures: &Vec<Vec<u8>>, texture_width: u32, texture_height: u32
           continue;
       }
       //Calculate distance projected on camera direction (Euclidean distance will give fisheye effect!)
-      if side == 0 { 
+      if let Some((distance, _)) = door_hit {
+          perp_wall_dist = distance;
+      }
+      else if side == 0 { 
           perp_wall_dist = (map_x as f32 - pos_x + (1.0 - step_x as f32) / 2.0) / ray_dir_x;
       }
       else { 
           perp_wall_dist = (map_y as f32 - pos_y + (1.0 - step_y as f32) / 2.0) / ray_dir_y;
       }
 
+      let visibility = fog::visibility(fog, perp_wall_dist);
+      // the wall face is lit like the cell it looks at, doors like their own
+      let light = if door_hit.is_some() {
+          light_map.at(map_x, map_y)
+      }
+      else if side == 0 {
+          light_map.at(map_x - step_x, map_y)
+      }
+      else {
+          light_map.at(map_x, map_y - step_y)
+      };
+
       //Calculate height of line to draw on screen
       let line_height = (h as f32/ perp_wall_dist) as usize;
 
@@ -356,7 +501,8 @@ fn render_walls(textures: &Vec<Vec<u8>>, texture_width: u32, texture_height: u32
       //choose wall color
       
       let tex_id = world_map[map_x as usize][map_y as usize] as usize;
-      let tex_id_layer = world_layer[map_x as usize][map_y as usize] as usize;
+      // the layer may still be the one of the previous level
+      let tex_id_layer = cell_value(world_layer, map_x, map_y);
 
       let mut wall_x; //where exactly the wall was hit
       if side == 0 { 
@@ -366,6 +512,10 @@ fn render_walls(textures: &Vec<Vec<u8>>, texture_width: u32, texture_height: u32
           wall_x = pos_x + perp_wall_dist * ray_dir_x;
       }
       wall_x -= wall_x.floor();
+      // the door texture slides with the door
+      if let Some((_, offset)) = door_hit {
+          wall_x = offset;
+      }
 
       //draw the pixels of the stripe as a vertical line
       let mut tex_x = (wall_x * texture_width as f32) as i32;
@@ -381,22 +531,17 @@ fn render_walls(textures: &Vec<Vec<u8>>, texture_width: u32, texture_height: u32
           let tex_i = (tex_i * 3) as usize;
           let tex_id = tex_id - 1;
           let color_layer = if tex_id_layer != 0 {
-              let tex_id_layer = tex_id_layer - 1;
-              textures[tex_id_layer][tex_i] as u32 |
-                  ((textures[tex_id_layer][tex_i + 1] as u32) << 8) |
-                  ((textures[tex_id_layer][tex_i + 2] as u32) << 16)
+              texel(textures, tex_id_layer - 1, tex_i)
           } else {
               0
           };
           let color = if color_layer == 0 {
-              textures[tex_id][tex_i] as u32 |
-              ((textures[tex_id][tex_i + 1] as u32) << 8) |
-              ((textures[tex_id][tex_i + 2] as u32) << 16)
+              texel(textures, tex_id, tex_i)
           } else {
               color_layer
           };
           if (color & 0x00_fFFFFF) != 0 {
-              color_buff[y as usize * w + x as usize] = color;
+              color_buff[y as usize * w + x as usize] = fog::apply(fog, lighting::apply(color, light), visibility);
           }
       }
       depth_buff[x as usize] = perp_wall_dist;
@@ -411,6 +556,10 @@ enum InputEvent {
     StrafeRight,
     RotateRight,
     RotateLeft,
+    Vote(u8),
+    Scoreboard,
+    Chat,
+    Follow,
     Exit,
 }
 
@@ -432,6 +581,14 @@ fn crossterm_to_client_event(option_event: Option<crossterm_input::InputEvent>)
             Some(InputEvent::StrafeRight),
         Some(crossterm_input::InputEvent::Keyboard(crossterm_input::KeyEvent::CtrlLeft)) => 
             Some(InputEvent::StrafeLeft),
+        Some(crossterm_input::InputEvent::Keyboard(crossterm_input::KeyEvent::Char(c))) if c >= '1' && c <= '9' =>
+            Some(InputEvent::Vote(c as u8 - b'1')),
+        Some(crossterm_input::InputEvent::Keyboard(crossterm_input::KeyEvent::Tab)) =>
+            Some(InputEvent::Scoreboard),
+        Some(crossterm_input::InputEvent::Keyboard(crossterm_input::KeyEvent::Char('t'))) =>
+            Some(InputEvent::Chat),
+        Some(crossterm_input::InputEvent::Keyboard(crossterm_input::KeyEvent::Char('f'))) =>
+            Some(InputEvent::Follow),
         _ => None
     }
 }
@@ -472,93 +629,75 @@ fn girls_to_client_event(gamepad_option: Option<gilrs::Gamepad>) -> Option<Input
 }
 
 
-fn move_player(option_event: Option<InputEvent>,world_map: &Vec<Vec<u8>>, pos_x: &mut f32, pos_y: &mut f32, dir_x: &mut f32, dir_y: &mut f32, plane_x: &mut f32, plane_y: &mut f32, packet_sender: &Sender<Packet>, server: &std::net::SocketAddr) -> f32 {
-    let rot_speed : f32 = 0.1;
-    let move_speed : f32 = 0.1;
+/// Handles an input, returns the resulting speed and the movement made, if any.
+/// Spectators fly through walls and fire no triggers.
+fn move_player(option_event: Option<InputEvent>,world_map: &Vec<Vec<u8>>, doors: &Vec<Door>, pos_x: &mut f32, pos_y: &mut f32, dir_x: &mut f32, dir_y: &mut f32, plane_x: &mut f32, plane_y: &mut f32, packet_sender: &Sender<Packet>, server: &std::net::SocketAddr, spectating: bool) -> (f32, Option<Movement>) {
+    let movement = match option_event {
+        Some(InputEvent::Action) if spectating => None,
+        Some(InputEvent::Action) => {
+            let pos = ClientMessage::MessageAction(*pos_x, *pos_y, 1);
+            send(packet_sender, *server, &pos);
+            None
+        },
+        Some(InputEvent::Vote(index)) => {
+            let vote = ClientMessage::MessageVote(index);
+            send(packet_sender, *server, &vote);
+            None
+        },
+        Some(InputEvent::Scoreboard) => None,
+        Some(InputEvent::Chat) => None,
+        Some(InputEvent::Follow) => None,
+        Some(InputEvent::Exit) => {
+            send(packet_sender, *server, &ClientMessage::MessageBye);
+            // give the socket a chance to send it before we are gone
+            thread::sleep(Duration::from_millis(100));
+            rmcup();
+            let _screen = crossterm_input::RawScreen::disable_raw_mode();
+            std::process::exit(1);
+        },
+        Some(InputEvent::WalkForward) => Some(Movement::WalkForward),
+        Some(InputEvent::WalkBackward) => Some(Movement::WalkBackward),
+        Some(InputEvent::RotateRight) => Some(Movement::RotateRight),
+        Some(InputEvent::RotateLeft) => Some(Movement::RotateLeft),
+        Some(InputEvent::StrafeRight) => Some(Movement::StrafeRight),
+        Some(InputEvent::StrafeLeft) => Some(Movement::StrafeLeft),
+        None => None,
+    };
     let mut res = 0.0;
-        match option_event {
-            Some(InputEvent::Action) => {
-                let pos = ClientMessage::MessageAction(*pos_x, *pos_y, 1);
-                let pos_ser = bincode::serialize(&pos).unwrap();
-                packet_sender.send(Packet::reliable_unordered(*server, pos_ser)).unwrap();
-            },
-            Some(InputEvent::WalkForward) => {
-                if world_map[(*pos_x + *dir_x * move_speed) as usize][(*pos_y) as usize] == 0 { 
-                    *pos_x += *dir_x * move_speed;
-                }
-                if world_map[(*pos_x) as usize][(*pos_y + *dir_y * move_speed) as usize] == 0 {
-                    *pos_y += *dir_y * move_speed;
-                }
-                res = move_speed;
-            },
-            Some(InputEvent::WalkBackward) => {
-                if world_map[(*pos_x - *dir_x * move_speed) as usize][(*pos_y) as usize] == 0 { 
-                    *pos_x -= *dir_x * move_speed;
-                }
-                if world_map[(*pos_x) as usize][(*pos_y - *dir_y * move_speed) as usize] == 0 {
-                    *pos_y -= *dir_y * move_speed;
-                }
-                res = -move_speed;
-            },
-            Some(InputEvent::Exit) => {
-                rmcup();
-                let _screen = crossterm_input::RawScreen::disable_raw_mode();
-                std::process::exit(1);
-            },
-            Some(InputEvent::RotateRight) => {
-                let old_dir_x = *dir_x;
-                *dir_x = *dir_x * (-rot_speed).cos() - *dir_y * (-rot_speed).sin();
-                *dir_y = old_dir_x * (-rot_speed).sin() + *dir_y * (-rot_speed).cos();
-                let old_plane_x = *plane_x;
-                *plane_x = *plane_x * (-rot_speed).cos() - *plane_y * (-rot_speed).sin();
-                *plane_y = old_plane_x * (-rot_speed).sin() + *plane_y * (-rot_speed).cos();
-            },
-            Some(InputEvent::RotateLeft) => {
-                let old_dir_x = *dir_x;
-                *dir_x = *dir_x * (rot_speed).cos()  - *dir_y * (rot_speed).sin();
-                *dir_y = old_dir_x * (rot_speed).sin() + *dir_y * (rot_speed).cos();
-                let old_plane_x = *plane_x;
-                *plane_x = *plane_x * (rot_speed).cos()  - *plane_y * (rot_speed).sin();
-                *plane_y = old_plane_x * (rot_speed).sin() + *plane_y * (rot_speed).cos();
-            },
-            Some(InputEvent::StrafeRight) => {
-                let _dir_x = *dir_x * (3.14/2.0 as f32).cos()  - *dir_y * (3.14/2.0 as f32).sin();
-                let _dir_y = *dir_x * (3.14/2.0 as f32).sin() + *dir_y * (3.14/2.0 as f32).cos();
-                if world_map[(*pos_x - _dir_x * move_speed) as usize][(*pos_y) as usize] == 0 { 
-                    *pos_x -= _dir_x * move_speed;
-                }
-                if world_map[(*pos_x) as usize][(*pos_y - _dir_y * move_speed) as usize] == 0 {
-                    *pos_y -= _dir_y * move_speed;
-                }
-                res = -move_speed;
-            },
-            Some(InputEvent::StrafeLeft) => {
-                let _dir_x = *dir_x * (3.14/2.0 as f32).cos()  - *dir_y * (3.14/2.0 as f32).sin();
-                let _dir_y = *dir_x * (3.14/2.0 as f32).sin() + *dir_y * (3.14/2.0 as f32).cos();
-                if world_map[(*pos_x - _dir_x * move_speed) as usize][(*pos_y) as usize] == 0 { 
-                    *pos_x += _dir_x * move_speed;
-                }
-                if world_map[(*pos_x) as usize][(*pos_y - _dir_y * move_speed) as usize] == 0 {
-                    *pos_y += _dir_y * move_speed;
-                }
-                res = -move_speed;
-            },
-            _ => {}
+    if let Some(movement) = movement {
+        let mut camera = Camera { pos_x: *pos_x, pos_y: *pos_y, dir_x: *dir_x, dir_y: *dir_y, plane_x: *plane_x, plane_y: *plane_y };
+        if spectating {
+            simulation::fly(movement, world_map, &mut camera);
+        }
+        else {
+            res = simulation::apply(movement, world_map, doors, &mut camera);
         }
-        res
+        *pos_x = camera.pos_x;
+        *pos_y = camera.pos_y;
+        *dir_x = camera.dir_x;
+        *dir_y = camera.dir_y;
+        *plane_x = camera.plane_x;
+        *plane_y = camera.plane_y;
+    }
+    (res, movement)
 }
 
-fn load_texture_from_http_wip(url: String) -> Vec<Vec<u8>> {
+/// Reads the `pics/<n>.png` textures of a texture pack, and the sky picture called `sky` if there is one
+fn read_texture_pack<R: Read + std::io::Seek>(mut archive: zip::ZipArchive<R>, sky: Option<&str>) -> (Vec<Vec<u8>>, Option<Sky>) {
     let texture_size = 64;
-    let resp = reqwest::blocking::get(&url).unwrap().bytes().unwrap();
-    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(resp)).unwrap();
     let mut textures = HashMap::new();
+    let mut sky_picture = None;
     for i in 0..archive.len() {
         let mut file = archive.by_index(i).unwrap();
-        if !(&*file.name()).ends_with('/') {
+        let name = String::from(file.name());
+        if !name.ends_with('/') {
             let mut bytes = vec![0; file.size() as usize];
             file.read(&mut bytes).unwrap();
-            let someindex = scan_fmt_some!(file.name(), "pics/{d}.png", usize);
+            if Some(name.as_str()) == sky {
+                sky_picture = Sky::new(image::load(std::io::Cursor::new(bytes),  image::ImageFormat::PNG).unwrap());
+                continue;
+            }
+            let someindex = scan_fmt_some!(&name, "pics/{d}.png", usize);
             let index = someindex.unwrap();
             textures.insert(index,
                 image::load(std::io::Cursor::new(bytes),  image::ImageFormat::PNG).unwrap().resize(texture_size, texture_size, FilterType::Nearest).to_rgb().into_raw());
@@ -566,43 +705,26 @@ fn load_texture_from_http_wip(url: String) -> Vec<Vec<u8>> {
     }
     let mut v: Vec<_> = textures.into_iter().collect();
     v.sort_by(|x,y| x.0.cmp(&y.0));
-    v.into_iter().map(|x| x.1).collect()
-        /*
-    let mut file = BufReader::new(File::open(&url).unwrap());
-    zip::ZipArchive::new(file).unwrap()
-        */
+    (v.into_iter().map(|x| x.1).collect(), sky_picture)
 }
 
-fn load_texture_from_local_file(url: String) -> Vec<Vec<u8>> {
-    let texture_size = 64;
+fn load_texture_from_http_wip(url: String, sky: Option<&str>) -> (Vec<Vec<u8>>, Option<Sky>) {
+    let resp = reqwest::blocking::get(&url).unwrap().bytes().unwrap();
+    read_texture_pack(zip::ZipArchive::new(std::io::Cursor::new(resp)).unwrap(), sky)
+}
+
+fn load_texture_from_local_file(url: String, sky: Option<&str>) -> (Vec<Vec<u8>>, Option<Sky>) {
     let file = File::open(&url).unwrap();
-    let mut archive = zip::ZipArchive::new(file).unwrap();
-    let mut textures = HashMap::new();
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i).unwrap();
-        if !(&*file.name()).ends_with('/') {
-            let mut bytes = vec![0; file.size() as usize];
-            file.read(&mut bytes).unwrap();
-            let someindex = scan_fmt_some!(file.name(), "pics/{d}.png", usize);
-            let index = someindex.unwrap();
-            textures.insert(index,
-                image::load(std::io::Cursor::new(bytes),  image::ImageFormat::PNG).unwrap().resize(texture_size, texture_size, FilterType::Nearest).to_rgb().into_raw());
-        }
-    }
-    let mut v: Vec<_> = textures.into_iter().collect();
-    v.sort_by(|x,y| x.0.cmp(&y.0));
-    v.into_iter().map(|x| x.1).collect()
-        /*
-        */
+    read_texture_pack(zip::ZipArchive::new(file).unwrap(), sky)
 }
 
 
-fn load_textures(url: String) -> Vec<Vec<u8>> {
+fn load_textures(url: String, sky: Option<&str>) -> (Vec<Vec<u8>>, Option<Sky>) {
     if url.starts_with("http") {
-        load_texture_from_http_wip(url)
+        load_texture_from_http_wip(url, sky)
     }
     else {
-        load_texture_from_local_file(url)
+        load_texture_from_local_file(url, sky)
     }
 }
 
@@ -636,7 +758,7 @@ fn play_sound(sound_device: &rodio::Device, path: String) {
     rodio::play_raw(&sound_device, coin_sound_samples);
 }
 
-fn render_portals(portals: &Vec<Vec<f32>>, portals_dests: &Vec<Vec<f32>>, portal_color_buff: &mut Vec<u32>, portal_depth_buff: &mut Vec<f32>, portal_width: usize, portal_height: usize, portals_textures: &mut Vec<Vec<u8>>, textures: &Vec<Vec<u8>>, character_textures: &Vec<Vec<u8>>, goldcoin_textures: &Vec<Vec<u8>>,torch_textures: &Vec<Vec<u8>>, sprites: &Vec<Vec<f32>>, characters: &Vec<Vec<f32>>, gold_coins: &Vec<Vec<f32>>, torches: &Vec<Vec<f32>>, tex_width: u32, tex_height: u32, coin_width: u32, coin_height: u32, torch_width: u32, torch_height: u32, world_map: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>, pos_x: &mut f32, pos_y: &mut f32, dir_x:f32, dir_y: f32, plane_x: f32, plane_y: f32, t: i32) {
+fn render_portals(portals: &Vec<Vec<f32>>, portals_dests: &Vec<Vec<f32>>, portal_color_buff: &mut Vec<u32>, portal_depth_buff: &mut Vec<f32>, portal_width: usize, portal_height: usize, portals_textures: &mut Vec<Vec<u8>>, textures: &Vec<Vec<u8>>, character_textures: &Vec<Vec<u8>>, goldcoin_textures: &Vec<Vec<u8>>,torch_textures: &Vec<Vec<u8>>, sprites: &Vec<Vec<f32>>, characters: &Vec<Vec<f32>>, gold_coins: &Vec<Vec<f32>>, torches: &Vec<Vec<f32>>, tex_width: u32, tex_height: u32, coin_width: u32, coin_height: u32, torch_width: u32, torch_height: u32, world_map: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>, doors: &Vec<Door>, floor: &Vec<Vec<u8>>, ceiling: &Vec<Vec<u8>>, sky: Option<&Sky>, fog: &Fog, light_map: &LightMap, pos_x: &mut f32, pos_y: &mut f32, dir_x:f32, dir_y: f32, plane_x: f32, plane_y: f32, t: i32) {
     for i in 0..portals.len() {
         let dist_x = *pos_x - portals[i][0];
         let dist_y = *pos_y - portals[i][1];
@@ -651,7 +773,7 @@ fn render_portals(portals: &Vec<Vec<f32>>, portals_dests: &Vec<Vec<f32>>, portal
                 (gold_coins, goldcoin_textures, coin_width, coin_height, true, false),
                 (torches, torch_textures, torch_width, torch_height, true, false),
             ];
-            render(&textures, tex_width, tex_height, &sprites_and_textures, portal_color_buff, portal_depth_buff, &world_map, &world_layer, portal_width, portal_height, dest_pos_x, dest_pos_y, dir_x, dir_y, plane_x, plane_y, start_dist, t);
+            render(&textures, tex_width, tex_height, &sprites_and_textures, portal_color_buff, portal_depth_buff, &world_map, &world_layer, &doors, &floor, &ceiling, sky, fog, light_map, portal_width, portal_height, dest_pos_x, dest_pos_y, dir_x, dir_y, plane_x, plane_y, start_dist, t);
             for y in 0..portal_height {
                 for x in 0..portal_width {
                     let base32 = y * portal_width + x;
@@ -666,13 +788,16 @@ fn render_portals(portals: &Vec<Vec<f32>>, portals_dests: &Vec<Vec<f32>>, portal
     }
 }
 
-fn render(textures: &Vec<Vec<u8>>, tex_width: u32, tex_height: u32, sprites_and_textures: &Vec<(&Vec<Vec<f32>>, &Vec<Vec<u8>>, u32, u32, bool, bool)>, color_buff: &mut Vec<u32>, depth_buff: &mut Vec<f32>, world_map: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>, w: usize, h: usize, pos_x: f32, pos_y: f32, dir_x:f32, dir_y: f32, plane_x: f32, plane_y: f32, start_dist: f32, t: i32) -> Option<usize> {
-    render_floor_ceiling(&textures, &world_layer, tex_width, tex_height, color_buff, w, h, pos_x, pos_y, dir_x, dir_y, plane_x, plane_y);
-    render_walls(&textures, tex_width, tex_height, &world_map, &world_layer, color_buff, depth_buff, w, h, pos_x, pos_y, dir_x, dir_y, plane_x, plane_y, start_dist);
-    render_sprites(&sprites_and_textures, color_buff, &depth_buff, w, h, pos_x, pos_y, dir_x, dir_y, plane_x, plane_y, t)
+fn render(textures: &Vec<Vec<u8>>, tex_width: u32, tex_height: u32, sprites_and_textures: &Vec<(&Vec<Vec<f32>>, &Vec<Vec<u8>>, u32, u32, bool, bool)>, color_buff: &mut Vec<u32>, depth_buff: &mut Vec<f32>, world_map: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>, doors: &Vec<Door>, floor: &Vec<Vec<u8>>, ceiling: &Vec<Vec<u8>>, sky: Option<&Sky>, fog: &Fog, light_map: &LightMap, w: usize, h: usize, pos_x: f32, pos_y: f32, dir_x:f32, dir_y: f32, plane_x: f32, plane_y: f32, start_dist: f32, t: i32) -> Option<usize> {
+    render_floor_ceiling(&textures, &world_layer, &floor, &ceiling, sky, fog, light_map, tex_width, tex_height, color_buff, w, h, pos_x, pos_y, dir_x, dir_y, plane_x, plane_y);
+    render_walls(&textures, tex_width, tex_height, &world_map, &world_layer, &doors, fog, light_map, color_buff, depth_buff, w, h, pos_x, pos_y, dir_x, dir_y, plane_x, plane_y, start_dist);
+    render_sprites(&sprites_and_textures, fog, light_map, color_buff, &depth_buff, w, h, pos_x, pos_y, dir_x, dir_y, plane_x, plane_y, t)
 }
 
-pub fn client(server_address: String, client_address: String, nickname: String) {
+/// Plays on `server` through `socket` as `nickname` wearing `skin`, or only watches with `spectate`.
+/// With `input_networking` the client sends its inputs and the server simulates its movement,
+/// other players are shown `interpolation_delay` in the past.
+pub fn client(server: std::net::SocketAddr, mut socket: Socket, nickname: String, skin: u8, input_networking: bool, interpolation_delay: Duration, spectate: bool) {
     smcup();
     let window_width = 640;
     let window_height = 320;
@@ -774,16 +899,28 @@ pub fn client(server_address: String, client_address: String, nickname: String)
         vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
             ];
 
+        // texture + 1 of the floor and ceiling of every cell, the server sends those of its level
+        let mut floor: Vec<Vec<u8>> = world_map.iter().map(|row| vec![4; row.len()]).collect();
+        let mut ceiling: Vec<Vec<u8>> = world_map.iter().map(|row| vec![7; row.len()]).collect();
+        // drawn where there is no ceiling, comes with the texture pack
+        let mut sky = None;
+        // none until the level tells us
+        let mut fog = Fog { color: [0, 0, 0], density: 0.0 };
+        // textures as they are until the level tells us, rebuilt on every torch frame
+        let mut lighting = Lighting { ambient: 1.0, lights: vec![] };
+        let mut torch_frame = 0;
+        let mut light_map = LightMap::new(&lighting, &world_map, torch_frame);
         let mut sprites = vec![];
+        // sliding doors, which keep moving between two updates from the server
+        let mut doors: Vec<Door> = vec![];
+        let mut doors_slid_at = Instant::now();
 
         let texture_size = 64; // must be a power of two so that fractional part in floor ceiling computation work
         let texture_width = 64;
         let texture_height = 64;
         let default_texture = image::open("free-pics/default.png").unwrap().resize(texture_size, texture_size, FilterType::Nearest).to_rgb().into_raw();
         let mut textures = vec![default_texture; 11];
-        let character_textures = vec![
-            image::open("free-pics/character2.png").unwrap().resize(texture_size, texture_size, FilterType::Nearest).to_rgb().into_raw(),
-        ];
+        let (skins, character_textures) = characters::load_skins(&["character2", "character"], texture_size);
         let coin_width = 32;
         let coin_height = 32;
         let goldcoin_textures = vec![
@@ -808,9 +945,8 @@ pub fn client(server_address: String, client_address: String, nickname: String)
             image::open("torch/Torch-05.png").unwrap().to_rgba().into_raw(),
         ];
 
-        let mut torches = vec![
-            vec![22.0, 10.1, 0.0]
-        ];
+        // where the level lights torches
+        let mut torches: Vec<Vec<f32>> = vec![];
 
         let mut portals = vec![
         ];
@@ -822,26 +958,43 @@ pub fn client(server_address: String, client_address: String, nickname: String)
         ];
 
         /* font stuff */
-        let mut text = String::from("loading...");
-        let text_width = 300;
-        let text_height = 40;
-        let mut text_expires = None;
-        let mut text_buff = generate_text(text, text_width, text_height);
+        let mut history = ChatHistory::new();
+        history.push(String::from("loading..."), Duration::from_secs(10));
+        // what we are typing, while the chat is open
+        let mut prompt: Option<Prompt> = None;
         /* end font struff */
 
         let mut gold_coins = vec![
         ];
-        let mut socket = Socket::bind(client_address.clone()).unwrap();
         let packet_sender = socket.get_packet_sender();
         let event_receiver = socket.get_event_receiver();
         let _thread = thread::spawn(move || socket.start_polling());
-        let server = server_address.parse().unwrap();
 
         let mut startup = true;
+        // capabilities both sides support, known once the server welcomed us
+        let mut capabilities = None;
+        // given by the server, lets us come back as the same player
+        let mut token = None;
+        let mut last_heard = Instant::now();
+        // packets from the server we could not read
+        let mut decode_errors = 0;
+        // inputs sent but not yet acknowledged by a snapshot, in input mode
+        let mut input_tick: u32 = 0;
+        let mut pending_inputs = VecDeque::new();
         let mut move_speed: f32 = 0.0;
-        let mut character_positions = vec![];
+        let mut snapshots = SnapshotBuffer::new(interpolation_delay);
+        // skin and rendered nickname of the other players
+        let mut remote_skins = HashMap::new();
+        let mut labels: HashMap<PlayerId, (String, usize, Vec<u32>)> = HashMap::new();
+        let label_height = 40;
+        // rendered scoreboard lines, shown while tab is toggled on
+        let mut scoreboard_lines = vec![];
+        let mut show_scoreboard = false;
+        // player whose eyes we see through when spectating, the camera flies freely otherwise
+        let mut following: Option<PlayerId> = None;
 
         let mut previous = Instant::now();
+        let mut position_sent = Instant::now();
 
         let mut gilrs = Gilrs::new().unwrap();
         let mut active_gamepad;
@@ -858,34 +1011,122 @@ pub fn client(server_address: String, client_address: String, nickname: String)
                     Ok(socket_event) => {
                         match socket_event {
                             SocketEvent::Packet(packet) => {
+                                if packet.addr() != server {
+                                    continue;
+                                }
                                 let received_data: &[u8] = packet.payload();
-                                let message = bincode::deserialize::<ServerMessage>(received_data).unwrap();
+                                let message = match decode::<ServerMessage>(received_data) {
+                                    Ok(message) => message,
+                                    Err(e) => {
+                                        decode_errors += 1;
+                                        // a stray packet is not worth giving up, a server sending nothing else is
+                                        if capabilities.is_none() && decode_errors >= MAX_DECODE_ERRORS {
+                                            quit(&format!("the server speaks an incompatible protocol (client version {}): {}", PROTOCOL_VERSION, e));
+                                        }
+                                        history.push(format!("malformed packet from the server ({} so far): {}", decode_errors, e), Duration::from_secs(5));
+                                        continue;
+                                    },
+                                };
+                                last_heard = Instant::now();
                                 match message {
+                                    ServerMessage::MessageWelcome(handshake) => {
+                                        capabilities = Some(handshake.capabilities);
+                                    },
+                                    ServerMessage::MessageIdentity(_, player_token) => {
+                                        token = Some(player_token);
+                                    },
+                                    ServerMessage::MessageRejected(handshake, reason) => {
+                                        quit(&format!("the server refused to let us in: {} (server version {}, client version {})", reason, handshake.protocol_version, PROTOCOL_VERSION));
+                                    },
+                                    ServerMessage::MessageKicked(reason) => {
+                                        quit(&format!("kicked by the server: {}", reason));
+                                    },
                                     ServerMessage::MessageSprites(s) => {
                                         sprites = s;
                                     },
-                                    ServerMessage::MessageTexturesZip(s) => {
-                                        textures = load_textures(s);
+                                    ServerMessage::MessageTexturesZip(url, sky_name) => {
+                                        let (pack, pack_sky) = load_textures(url, sky_name.as_ref().map(String::as_str));
+                                        textures = pack;
+                                        sky = pack_sky;
                                     },
                                     ServerMessage::MessageTeleport(pos) => {
-                                        dir_x = pos.dir_x;
-                                        dir_y = pos.dir_y;
-                                        pos_x = pos.x;
-                                        pos_y = pos.y;
+                                        let camera = Camera::from_position(&pos);
+                                        pos_x = camera.pos_x;
+                                        pos_y = camera.pos_y;
+                                        dir_x = camera.dir_x;
+                                        dir_y = camera.dir_y;
+                                        plane_x = camera.plane_x;
+                                        plane_y = camera.plane_y;
+                                    }
+                                    ServerMessage::MessageSnapshot(tick, pos) => {
+                                        // start from the server state and replay the inputs it has not seen yet
+                                        pending_inputs.retain(|(pending_tick, _)| *pending_tick > tick);
+                                        let camera = simulation::replay(&pos, pending_inputs.iter().map(|(_, command)| command), &world_map, &doors, &portals, &portals_dests);
+                                        pos_x = camera.pos_x;
+                                        pos_y = camera.pos_y;
+                                        dir_x = camera.dir_x;
+                                        dir_y = camera.dir_y;
+                                        plane_x = camera.plane_x;
+                                        plane_y = camera.plane_y;
                                     }
                                     ServerMessage::MessageText(txt, duration) => {
-                                        text = txt;
-                                        text_buff = generate_text(text, text_width, text_height);
-                                        text_expires = Some(Instant::now() + duration);
+                                        history.push(txt, duration);
                                     },
                                     ServerMessage::MessageWorldMap(map) => {
                                         world_map = map;
+                                        light_map = LightMap::new(&lighting, &world_map, torch_frame);
                                     },
                                     ServerMessage::MessageWorldLayer(layer) => {
                                         world_layer = layer;
                                     },
+                                    ServerMessage::MessageFloor(cells) => {
+                                        floor = cells;
+                                    },
+                                    ServerMessage::MessageCeiling(cells) => {
+                                        ceiling = cells;
+                                    },
+                                    ServerMessage::MessageFog(level_fog) => {
+                                        fog = level_fog;
+                                    },
+                                    ServerMessage::MessagePlaylist(names) => {
+                                        let choices: Vec<String> = names.iter().enumerate().take(9).map(|(i, name)| format!("{} {}", i + 1, name)).collect();
+                                        history.push(format!("vote for the next level: {}", choices.join(", ")), Duration::from_secs(10));
+                                    },
+                                    ServerMessage::MessageDoors(level_doors) => {
+                                        doors = level_doors;
+                                    },
+                                    ServerMessage::MessageLighting(level_lighting) => {
+                                        lighting = level_lighting;
+                                        torches = lighting.lights.iter().filter(|light| light.torch).map(|light| vec![light.x, light.y, torch_frame as f32]).collect();
+                                        light_map = LightMap::new(&lighting, &world_map, torch_frame);
+                                    },
+                                    ServerMessage::MessagePlayerJoined(nickname) => {
+                                        history.push(format!("{} joined", nickname), Duration::from_secs(5));
+                                    },
+                                    ServerMessage::MessagePlayerLeft(nickname, reason) => {
+                                        history.push(format!("{} {}", nickname, reason), Duration::from_secs(5));
+                                    },
+                                    ServerMessage::MessageChat(nickname, text) => {
+                                        history.push(format!("{}: {}", nickname, text), CHAT_DURATION);
+                                    },
+                                    ServerMessage::MessageTileChanges(changes) => {
+                                        let fits = |x: usize, y: usize, grid: &Vec<Vec<u8>>| x < grid.len() && y < grid[x].len();
+                                        if changes.iter().all(|(x, y, _, _)| fits(*x as usize, *y as usize, &world_map) && fits(*x as usize, *y as usize, &world_layer)) {
+                                            for (x, y, map, layer) in changes {
+                                                world_map[x as usize][y as usize] = map;
+                                                world_layer[x as usize][y as usize] = layer;
+                                            }
+                                        }
+                                        else {
+                                            // our world is not the one the server talks about, ask for the whole of it
+                                            send(&packet_sender, server, &ClientMessage::MessageResync);
+                                        }
+                                    },
                                     ServerMessage::MessageGoldCoins(gcs) => {
-                                        play_sound(&sound_device, String::from("sound/picked-coin-echo.mp3"));
+                                        // coins also move when a round starts or a coin respawns, only play the sound when one was picked
+                                        if gold_coins.iter().any(|gc: &Vec<f32>| !gcs.contains(&(gc[0], gc[1]))) {
+                                            play_sound(&sound_device, String::from("sound/picked-coin-echo.mp3"));
+                                        }
                                         gold_coins = vec![];
                                         for gc in gcs {
                                             gold_coins.push(vec![gc.0, gc.1, 0.0]);
@@ -899,11 +1140,28 @@ pub fn client(server_address: String, client_address: String, nickname: String)
                                             portals_textures.push(portal_color_buff_u8.clone());
                                         }
                                     }
-                                    ServerMessage::MessagePositions(positions) => {
-                                        character_positions = vec![];
-                                        for position in positions {
-                                            character_positions.push(position.1);
+                                    ServerMessage::MessageScoreboard(scores) => {
+                                        scoreboard_lines = scores.iter().map(|score| {
+                                            let line = format!("{}: {} coins, {} rounds won", score.nickname, score.coins, score.rounds_won);
+                                            let line_width = 25 * line.chars().count() + 10;
+                                            (line_width, generate_text(line, line_width as i32, label_height as i32))
+                                        }).collect();
+                                    },
+                                    ServerMessage::MessagePositions(time, players) => {
+                                        labels.retain(|id, _| players.contains_key(id));
+                                        remote_skins.clear();
+                                        let mut positions = HashMap::new();
+                                        for (id, player) in players {
+                                            // rendering text is slow, only do it when a nickname changes
+                                            if labels.get(&id).map_or(true, |label| label.0 != player.nickname) {
+                                                let label_width = 25 * player.nickname.chars().count() + 10;
+                                                let buffer = generate_text(player.nickname.clone(), label_width as i32, label_height as i32);
+                                                labels.insert(id, (player.nickname, label_width, buffer));
+                                            }
+                                            remote_skins.insert(id, player.skin);
+                                            positions.insert(id, player.position);
                                         }
+                                        snapshots.push(Instant::now(), time, positions);
                                     },
                                 }
                             },
@@ -916,43 +1174,68 @@ pub fn client(server_address: String, client_address: String, nickname: String)
                     }
                 }
             }
+            let now = Instant::now();
+            simulation::slide_doors(&mut doors, now - doors_slid_at);
+            doors_slid_at = now;
+            let spectating = capabilities.map_or(spectate, |c| c & CAPABILITY_SPECTATE != 0);
             let mut characters = vec![];
-            character_positions = character_positions.iter().map ( |position| {
-                Position {
-                    x: position.x + position.speed * position.dir_x * 0.1,
-                    y: position.y + position.speed * position.dir_y * 0.1,
-                    dir_x: position.dir_x,
-                    dir_y: position.dir_y,
-                    speed: position.speed
+            let remote_positions = snapshots.positions(now, &world_map, &doors);
+            if let Some(followed) = following {
+                match remote_positions.iter().find(|(id, _)| *id == followed) {
+                    Some((_, position)) => {
+                        let camera = Camera::from_position(position);
+                        pos_x = camera.pos_x;
+                        pos_y = camera.pos_y;
+                        dir_x = camera.dir_x;
+                        dir_y = camera.dir_y;
+                        plane_x = camera.plane_x;
+                        plane_y = camera.plane_y;
+                    },
+                    None => {
+                        history.push(String::from("free camera"), Duration::from_secs(3));
+                        following = None;
+                    },
                 }
             }
-            ).collect::<Vec<Position>>();
-            for position in &character_positions {
-                characters.push(vec![position.x, position.y, 0.0]);
-            }
-            let now = Instant::now();
-            if let Some(expiration) = text_expires {
-                if now > expiration {
-                    text = String::from("");
-                    text_buff = generate_text(text, text_width, text_height);
-                    text_expires = None;
+            for (id, position) in &remote_positions {
+                // we would see the inside of the player we follow
+                if following == Some(*id) {
+                    continue;
                 }
+                let skin = remote_skins.get(id).cloned().unwrap_or(0) as usize;
+                let texture = skins[skin.min(skins.len() - 1)].texture(position, pos_x, pos_y, t);
+                characters.push(vec![position.x, position.y, texture as f32]);
             }
-            if (now - previous) > Duration::from_millis(500) {
-                if startup {
-                    let message = ClientMessage::MessageHello(nickname.clone());
-                    let message_ser = bincode::serialize(&message).unwrap();
-                    packet_sender.send(Packet::reliable_unordered(server, message_ser)).unwrap();
-                    startup = false;
-                }
+            if now - position_sent > POSITION_INTERVAL {
                 if previous_pos_x != pos_x || previous_pos_y != pos_y {
                     // play_sound(&sound_device, String::from("sound/wood03.ogg"));
                 }
                 let pos = ClientMessage::MessagePosition(Position { x : pos_x, y : pos_y, dir_x: dir_x, dir_y: dir_y, speed: move_speed });
                 previous_pos_x = pos_x;
                 previous_pos_y = pos_y;
-                let pos_ser = bincode::serialize(&pos).unwrap();
-                packet_sender.send(Packet::reliable_unordered(server, pos_ser)).unwrap();
+                send(&packet_sender, server, &pos);
+                position_sent = now;
+            }
+            if (now - previous) > Duration::from_millis(500) {
+                if startup || now - last_heard > SERVER_SILENCE {
+                    let offered = if spectate {
+                        (CAPABILITIES & !CAPABILITY_INPUT) | CAPABILITY_SPECTATE
+                    }
+                    else if input_networking {
+                        CAPABILITIES & !CAPABILITY_SPECTATE
+                    }
+                    else {
+                        CAPABILITIES & !CAPABILITY_INPUT & !CAPABILITY_SPECTATE
+                    };
+                    let handshake = Handshake { protocol_version: PROTOCOL_VERSION, capabilities: offered };
+                    let message = match token {
+                        Some(token) => ClientMessage::MessageReconnect(handshake, nickname.clone(), skin, token),
+                        None => ClientMessage::MessageHello(handshake, nickname.clone(), skin),
+                    };
+                    send(&packet_sender, server, &message);
+                    startup = false;
+                    last_heard = now;
+                }
                 previous = now;
                 for i in 0..gold_coins.len() {
                     gold_coins[i][2] += 1.0;
@@ -960,18 +1243,21 @@ pub fn client(server_address: String, client_address: String, nickname: String)
                         gold_coins[i][2] = 0.0;
                     }
                 }
+                torch_frame = (torch_frame + 1) % torch_textures.len();
                 for i in 0..torches.len() {
-                    torches[i][2] += 1.0;
-                    if torches[i][2] as usize >= torch_textures.len() {
-                        torches[i][2] = 0.0;
-                    }
+                    torches[i][2] = torch_frame as f32;
+                }
+                if !torches.is_empty() {
+                    // torch lights flicker with the flames
+                    light_map = LightMap::new(&lighting, &world_map, torch_frame);
                 }
             }
 
+            let input_mode = capabilities.map_or(false, |c| c & CAPABILITY_INPUT != 0);
             let start_time = Instant::now();
 
 
-            render_portals(&portals, &portals_dests, &mut portal_color_buff, &mut portal_depth_buff, portal_width, portal_height, &mut portals_textures, &textures, &character_textures, &goldcoin_textures, &torch_textures, &sprites, &characters, &gold_coins, &torches, texture_width, texture_height, coin_width, coin_height, torch_width, torch_height, &world_map, &world_layer, &mut pos_x, &mut pos_y, dir_x, dir_y, plane_x, plane_y, t);
+            render_portals(&portals, &portals_dests, &mut portal_color_buff, &mut portal_depth_buff, portal_width, portal_height, &mut portals_textures, &textures, &character_textures, &goldcoin_textures, &torch_textures, &sprites, &characters, &gold_coins, &torches, texture_width, texture_height, coin_width, coin_height, torch_width, torch_height, &world_map, &world_layer, &doors, &floor, &ceiling, sky.as_ref(), &fog, &light_map, &mut pos_x, &mut pos_y, dir_x, dir_y, plane_x, plane_y, t);
             let sprites_and_textures = vec![
                 (&sprites, &textures, texture_width, texture_height, false, false),
                 (&characters, &character_textures, texture_width, texture_height, false, false),
@@ -979,19 +1265,33 @@ pub fn client(server_address: String, client_address: String, nickname: String)
                 (&torches, &torch_textures, torch_width, torch_height, true, false),
                 (&portals, &portals_textures, portal_width as u32, portal_height as u32, true, true),
             ];
-            if let Some(portal_index) = render(&textures, texture_width, texture_height, &sprites_and_textures, &mut color_buff, &mut depth_buff, &world_map, &world_layer, window_width, window_height, pos_x, pos_y, dir_x, dir_y, plane_x, plane_y, 0.0, t) {
+            if let Some(portal_index) = render(&textures, texture_width, texture_height, &sprites_and_textures, &mut color_buff, &mut depth_buff, &world_map, &world_layer, &doors, &floor, &ceiling, sky.as_ref(), &fog, &light_map, window_width, window_height, pos_x, pos_y, dir_x, dir_y, plane_x, plane_y, 0.0, t) {
                 pos_x = portals_dests[portal_index][0];
                 pos_y = portals_dests[portal_index][1];
+                if input_mode {
+                    input_tick += 1;
+                    let command = InputCommand::TakePortal(portal_index as u8);
+                    pending_inputs.push_back((input_tick, command));
+                    send_input(&packet_sender, server, input_tick, command);
+                }
                 play_sound(&sound_device, String::from("sound/teleport.mp3"));
             }
-
-            for y in 0..text_height {
-                for x in 0..text_width {
-                    let pixel_i_dest = (y * window_width as i32 + x) as usize;
-                    let pixel_i_src = (y * text_width as i32 + x) as usize;
-                    color_buff[pixel_i_dest] |= text_buff[pixel_i_src];
+            for (id, position) in &remote_positions {
+                if following == Some(*id) {
+                    continue;
+                }
+                if let Some((_, label_width, label)) = labels.get(id) {
+                    render_label(label, *label_width, label_height, position.x, position.y, &mut color_buff, &depth_buff, window_width, window_height, pos_x, pos_y, dir_x, dir_y, plane_x, plane_y);
                 }
             }
+
+            if show_scoreboard {
+                render_scoreboard(&scoreboard_lines, label_height, &mut color_buff, window_width, window_height);
+            }
+            history.render(Instant::now(), &mut color_buff, window_width, window_height);
+            if let Some(prompt) = &prompt {
+                prompt.render(&mut color_buff, window_width, window_height);
+            }
             print!("\x1b[{};0f", 0);
             engine.render(&|x, y| {
                 let start = (y * window_height as u32 / term_height * window_width as u32 + (x * window_width as u32 / term_width))
@@ -1009,12 +1309,65 @@ pub fn client(server_address: String, client_address: String, nickname: String)
                 gamepad_id = Some(id);
             }
             active_gamepad = gamepad_id.map(|id| gilrs.gamepad(id));
-            if let Some(event) = girls_to_client_event(active_gamepad) {
-                move_speed = move_player(Some(event), &world_map, &mut pos_x, &mut pos_y, &mut dir_x, &mut dir_y, &mut plane_x, &mut plane_y, &packet_sender, &server)
+            // while the chat is open, keys are typed in it
+            let option_event = if let Some(typing) = &mut prompt {
+                match typing.edit(reader.next()) {
+                    PromptEvent::Submitted => {
+                        let text = typing.text.trim();
+                        if let Some(rcon) = text.strip_prefix("/rcon ") {
+                            // /rcon <password> <command>
+                            let mut words = rcon.trim().splitn(2, ' ');
+                            let password = words.next().unwrap_or("");
+                            let command = words.next().unwrap_or("");
+                            send(&packet_sender, server, &ClientMessage::MessageRcon(String::from(password), String::from(command)));
+                        }
+                        else if !text.is_empty() {
+                            send(&packet_sender, server, &ClientMessage::MessageChat(typing.text.clone()));
+                        }
+                        prompt = None;
+                    },
+                    PromptEvent::Cancelled => {
+                        prompt = None;
+                    },
+                    PromptEvent::Edited | PromptEvent::Ignored => {},
+                }
+                None
             }
             else {
-                let option_event = crossterm_to_client_event(reader.next());
-                move_speed = move_player(option_event, &world_map, &mut pos_x, &mut pos_y, &mut dir_x, &mut dir_y, &mut plane_x, &mut plane_y, &packet_sender, &server)
+                match girls_to_client_event(active_gamepad) {
+                    Some(event) => Some(event),
+                    None => match crossterm_to_client_event(reader.next()) {
+                        Some(InputEvent::Vote(_)) if capabilities.map_or(true, |c| c & CAPABILITY_VOTE == 0) => None,
+                        event => event,
+                    },
+                }
+            };
+            match option_event {
+                Some(InputEvent::Scoreboard) => show_scoreboard = !show_scoreboard,
+                Some(InputEvent::Chat) => prompt = Some(Prompt::new()),
+                Some(InputEvent::Follow) if spectating => {
+                    // cycles through the players in id order, then back to the free camera
+                    let mut ids: Vec<PlayerId> = remote_positions.iter().map(|(id, _)| *id).collect();
+                    ids.sort();
+                    following = match following {
+                        Some(followed) => ids.into_iter().find(|id| *id > followed),
+                        None => ids.into_iter().next(),
+                    };
+                    let text = match following.and_then(|id| labels.get(&id)) {
+                        Some((nickname, _, _)) => format!("following {}", nickname),
+                        None => String::from("free camera"),
+                    };
+                    history.push(text, Duration::from_secs(3));
+                },
+                _ => {},
+            }
+            let (speed, movement) = move_player(option_event, &world_map, &doors, &mut pos_x, &mut pos_y, &mut dir_x, &mut dir_y, &mut plane_x, &mut plane_y, &packet_sender, &server, spectating);
+            move_speed = speed;
+            if let (true, Some(movement)) = (input_mode, movement) {
+                input_tick += 1;
+                let command = InputCommand::Move(movement);
+                pending_inputs.push_back((input_tick, command));
+                send_input(&packet_sender, server, input_tick, command);
             }
 
         }
@@ -0,0 +1,71 @@
+use std::f32::consts::PI;
+use std::path::Path;
+use image::GenericImageView;
+use image::imageops::FilterType;
+use crate::protocol::Position;
+
+/// Directions in a sprite sheet, the first one facing the viewer, then turning counterclockwise
+const DIRECTIONS: usize = 8;
+/// rendered frames each walk cycle frame stays on screen
+const FRAMES_PER_STEP: i32 = 8;
+
+/// Where the textures of a skin start in the character textures.
+/// Textures are stored frame by frame, each frame holding one texture per direction.
+pub struct Skin {
+    first_texture: usize,
+    directions: usize,
+    frames: usize,
+}
+
+/// Loads `free-pics/<name>-sheet.png` if it exists, otherwise the single billboard `free-pics/<name>.png`.
+/// A sheet has 8 square cells per row, one per direction, and one row per frame:
+/// the first row is the player standing, the next ones the walk cycle.
+fn load_skin(name: &str, texture_size: u32, textures: &mut Vec<Vec<u8>>) -> Skin {
+    let first_texture = textures.len();
+    let sheet_path = format!("free-pics/{}-sheet.png", name);
+    if !Path::new(&sheet_path).exists() {
+        let path = format!("free-pics/{}.png", name);
+        textures.push(image::open(path).unwrap().resize(texture_size, texture_size, FilterType::Nearest).to_rgb().into_raw());
+        return Skin { first_texture: first_texture, directions: 1, frames: 1 };
+    }
+    let mut sheet = image::open(sheet_path).unwrap();
+    let cell = sheet.width() / DIRECTIONS as u32;
+    let frames = (sheet.height() / cell).max(1) as usize;
+    for frame in 0..frames {
+        for direction in 0..DIRECTIONS {
+            let texture = sheet.crop(direction as u32 * cell, frame as u32 * cell, cell, cell);
+            textures.push(texture.resize_exact(texture_size, texture_size, FilterType::Nearest).to_rgb().into_raw());
+        }
+    }
+    Skin { first_texture: first_texture, directions: DIRECTIONS, frames: frames }
+}
+
+/// Loads the skins players can pick, in order, returns them and their textures
+pub fn load_skins(names: &[&str], texture_size: u32) -> (Vec<Skin>, Vec<Vec<u8>>) {
+    let mut textures = vec![];
+    let skins = names.iter().map(|name| load_skin(name, texture_size, &mut textures)).collect();
+    (skins, textures)
+}
+
+impl Skin {
+    /// Texture showing a player at `position` seen from (viewer_x, viewer_y), `t` being the frame counter
+    pub fn texture(&self, position: &Position, viewer_x: f32, viewer_y: f32, t: i32) -> usize {
+        let direction = if self.directions == 1 {
+            0
+        }
+        else {
+            // angle from where the player faces to where the viewer stands
+            let to_viewer = (viewer_y - position.y).atan2(viewer_x - position.x);
+            let facing = position.dir_y.atan2(position.dir_x);
+            let angle = (to_viewer - facing).rem_euclid(2.0 * PI);
+            (angle / (2.0 * PI / self.directions as f32)).round() as usize % self.directions
+        };
+        let frame = if self.frames == 1 || position.speed == 0.0 {
+            0
+        }
+        else {
+            1 + (t / FRAMES_PER_STEP) as usize % (self.frames - 1)
+        };
+        self.first_texture + frame * self.directions + direction
+    }
+}
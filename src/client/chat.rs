@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use crossterm_input::{InputEvent, KeyEvent};
+use crate::protocol::MAX_CHAT_LENGTH;
+use super::{blit_text, generate_text};
+
+/// lines kept on screen, older ones scroll out
+const MAX_LINES: usize = 8;
+/// height of a rendered line before it is halved on screen
+const LINE_HEIGHT: usize = 40;
+
+/// Renders a line of text, returns its width and pixels
+fn render_line(text: String) -> (usize, Vec<u32>) {
+    let width = 25 * text.chars().count() + 10;
+    (width, generate_text(text, width as i32, LINE_HEIGHT as i32))
+}
+
+/// Server messages and chat lines shown at the top left of the screen until they expire
+pub struct ChatHistory {
+    lines: VecDeque<(usize, Vec<u32>, Instant)>,
+}
+
+impl ChatHistory {
+    pub fn new() -> ChatHistory {
+        ChatHistory { lines: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, text: String, duration: Duration) {
+        let (width, pixels) = render_line(text);
+        self.lines.push_back((width, pixels, Instant::now() + duration));
+        while self.lines.len() > MAX_LINES {
+            self.lines.pop_front();
+        }
+    }
+
+    pub fn render(&mut self, now: Instant, color_buff: &mut Vec<u32>, w: usize, h: usize) {
+        self.lines.retain(|(_, _, expires)| *expires > now);
+        for (i, (width, pixels, _)) in self.lines.iter().enumerate() {
+            blit_text(pixels, *width, LINE_HEIGHT, 5, (i * LINE_HEIGHT / 2) as i32, color_buff, w, h);
+        }
+    }
+}
+
+pub enum PromptEvent {
+    Edited,
+    Submitted,
+    Cancelled,
+    Ignored,
+}
+
+/// What we are typing, shown at the bottom of the screen
+pub struct Prompt {
+    pub text: String,
+    rendered: (usize, Vec<u32>),
+}
+
+impl Prompt {
+    pub fn new() -> Prompt {
+        Prompt { text: String::new(), rendered: render_line(String::from("> ")) }
+    }
+
+    /// Applies a key press: characters are typed, enter sends and escape gives up
+    pub fn edit(&mut self, event: Option<InputEvent>) -> PromptEvent {
+        match event {
+            Some(InputEvent::Keyboard(KeyEvent::Char(c))) if !c.is_control() && self.text.chars().count() < MAX_CHAT_LENGTH => {
+                self.text.push(c);
+            },
+            Some(InputEvent::Keyboard(KeyEvent::Backspace)) => {
+                self.text.pop();
+            },
+            Some(InputEvent::Keyboard(KeyEvent::Enter)) => return PromptEvent::Submitted,
+            Some(InputEvent::Keyboard(KeyEvent::Esc)) => return PromptEvent::Cancelled,
+            _ => return PromptEvent::Ignored,
+        }
+        self.rendered = render_line(format!("> {}", self.text));
+        PromptEvent::Edited
+    }
+
+    pub fn render(&self, color_buff: &mut Vec<u32>, w: usize, h: usize) {
+        blit_text(&self.rendered.1, self.rendered.0, LINE_HEIGHT, 5, (h - LINE_HEIGHT / 2 - 5) as i32, color_buff, w, h);
+    }
+}
@@ -0,0 +1,18 @@
+use crate::protocol::Fog;
+
+/// Share of a color still seen at `distance`, the rest being fog
+pub fn visibility(fog: &Fog, distance: f32) -> f32 {
+    (-fog.density * distance.abs()).exp()
+}
+
+/// `color` seen through the fog, `visibility` coming from `visibility`
+pub fn apply(fog: &Fog, color: u32, visibility: f32) -> u32 {
+    if visibility >= 1.0 {
+        return color;
+    }
+    let channel = |shift: u32, fog_channel: u8| {
+        let value = (color >> shift & 0xff) as f32;
+        ((value * visibility + fog_channel as f32 * (1.0 - visibility)) as u32) << shift
+    };
+    channel(0, fog.color[0]) | channel(8, fog.color[1]) | channel(16, fog.color[2])
+}
@@ -0,0 +1,221 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use crate::protocol::{Door, PlayerId, Position};
+use crate::simulation;
+
+/// Longest a remote player keeps moving past its last known position
+const MAX_EXTRAPOLATION: Duration = Duration::from_millis(250);
+/// snapshots kept, more than enough to cover the interpolation delay
+const MAX_SNAPSHOTS: usize = 32;
+/// distance moved between two collision checks while extrapolating
+const COLLISION_STEP: f32 = 0.1;
+/// a player moving further than this between two snapshots took a portal
+const TELEPORT_DISTANCE: f32 = 2.0;
+/// server time going back this many milliseconds means the server restarted, not a late packet
+const CLOCK_RESET: u64 = 5000;
+
+struct Snapshot {
+    /// server time, in milliseconds
+    time: u64,
+    positions: HashMap<PlayerId, Position>,
+}
+
+/// Remote players positions received from the server, shown `delay` in the past
+/// so that there usually are two snapshots to interpolate between
+pub struct SnapshotBuffer {
+    delay: Duration,
+    started: Instant,
+    /// server time minus local time in milliseconds, from the fastest snapshot seen
+    offset: Option<i64>,
+    snapshots: VecDeque<Snapshot>,
+}
+
+fn distance(a: &Position, b: &Position) -> f32 {
+    ((b.x - a.x) * (b.x - a.x) + (b.y - a.y) * (b.y - a.y)).sqrt()
+}
+
+fn lerp(from: &Position, to: &Position, t: f32) -> Position {
+    if distance(from, to) > TELEPORT_DISTANCE {
+        return to.clone();
+    }
+    let dir_x = from.dir_x + (to.dir_x - from.dir_x) * t;
+    let dir_y = from.dir_y + (to.dir_y - from.dir_y) * t;
+    let norm = (dir_x * dir_x + dir_y * dir_y).sqrt();
+    let (dir_x, dir_y) = if norm > 0.0 { (dir_x / norm, dir_y / norm) } else { (to.dir_x, to.dir_y) };
+    Position {
+        x: from.x + (to.x - from.x) * t,
+        y: from.y + (to.y - from.y) * t,
+        dir_x: dir_x,
+        dir_y: dir_y,
+        speed: to.speed,
+    }
+}
+
+/// Keeps `position` moving at (velocity_x, velocity_y) units per second for `seconds`, stopping at walls
+fn extrapolate(position: &Position, velocity_x: f32, velocity_y: f32, seconds: f32, world_map: &Vec<Vec<u8>>, doors: &Vec<Door>) -> Position {
+    let (dx, dy) = (velocity_x * seconds, velocity_y * seconds);
+    let steps = ((dx * dx + dy * dy).sqrt() / COLLISION_STEP).ceil().max(1.0);
+    let (mut x, mut y) = (position.x, position.y);
+    for _ in 0..steps as usize {
+        let next = simulation::slide(world_map, doors, x, y, dx / steps, dy / steps);
+        x = next.0;
+        y = next.1;
+    }
+    Position { x: x, y: y, ..position.clone() }
+}
+
+impl SnapshotBuffer {
+    pub fn new(delay: Duration) -> SnapshotBuffer {
+        SnapshotBuffer { delay: delay, started: Instant::now(), offset: None, snapshots: VecDeque::new() }
+    }
+
+    fn local_time(&self, now: Instant) -> i64 {
+        (now - self.started).as_millis() as i64
+    }
+
+    /// Stores the positions the server had at `time`, older snapshots arriving late are dropped.
+    /// A much older time means the server clock started over: what we had is forgotten.
+    pub fn push(&mut self, now: Instant, time: u64, positions: HashMap<PlayerId, Position>) {
+        if self.snapshots.back().map_or(false, |last| time + CLOCK_RESET <= last.time) {
+            self.snapshots.clear();
+            self.offset = None;
+        }
+        if self.snapshots.back().map_or(false, |last| time <= last.time) {
+            return;
+        }
+        // the snapshot which took the least time to arrive gives the best clock estimate
+        let offset = time as i64 - self.local_time(now);
+        self.offset = Some(self.offset.map_or(offset, |previous| previous.max(offset)));
+        self.snapshots.push_back(Snapshot { time: time, positions: positions });
+        while self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Where remote players should be drawn at `now`
+    pub fn positions(&self, now: Instant, world_map: &Vec<Vec<u8>>, doors: &Vec<Door>) -> Vec<(PlayerId, Position)> {
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => return vec![],
+        };
+        let render_time = self.local_time(now) + offset - self.delay.as_millis() as i64;
+        let from = match self.snapshots.iter().rposition(|snapshot| snapshot.time as i64 <= render_time) {
+            Some(index) => index,
+            // still before the oldest snapshot
+            None => return self.snapshots.front().map_or(vec![], |snapshot| snapshot.positions.iter().map(|(key, position)| (*key, position.clone())).collect()),
+        };
+        let from_snapshot = &self.snapshots[from];
+        if let Some(to_snapshot) = self.snapshots.get(from + 1) {
+            let t = (render_time - from_snapshot.time as i64) as f32 / (to_snapshot.time - from_snapshot.time) as f32;
+            return from_snapshot.positions.iter().map(|(key, position)| {
+                match to_snapshot.positions.get(key) {
+                    Some(next) => (*key, lerp(position, next, t)),
+                    None => (*key, position.clone()),
+                }
+            }).collect();
+        }
+        // no newer snapshot yet, keep players moving the way they did for a little while
+        let elapsed = Duration::from_millis((render_time - from_snapshot.time as i64) as u64).min(MAX_EXTRAPOLATION);
+        let previous_snapshot = if from > 0 { self.snapshots.get(from - 1) } else { None };
+        from_snapshot.positions.iter().map(|(key, position)| {
+            let previous = previous_snapshot.and_then(|snapshot| snapshot.positions.get(key).map(|previous| (snapshot.time, previous)));
+            match previous {
+                Some((time, previous)) if distance(previous, position) <= TELEPORT_DISTANCE => {
+                    let seconds = (from_snapshot.time - time) as f32 / 1000.0;
+                    let velocity_x = (position.x - previous.x) / seconds;
+                    let velocity_y = (position.y - previous.y) / seconds;
+                    (*key, extrapolate(position, velocity_x, velocity_y, elapsed.as_secs_f32(), world_map, doors))
+                },
+                _ => (*key, position.clone()),
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 5 by 5 cells, walls around an empty room
+    fn world_map() -> Vec<Vec<u8>> {
+        (0..5).map(|x| (0..5).map(|y| if x == 0 || y == 0 || x == 4 || y == 4 { 1 } else { 0 }).collect()).collect()
+    }
+
+    fn at(x: f32, y: f32) -> HashMap<PlayerId, Position> {
+        let mut positions = HashMap::new();
+        positions.insert(1, Position { x: x, y: y, dir_x: 0.0, dir_y: 1.0, speed: 1.0 });
+        positions
+    }
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    /// x of player 1 drawn at `now`
+    fn x(buffer: &SnapshotBuffer, now: Instant) -> f32 {
+        buffer.positions(now, &world_map(), &vec![])[0].1.x
+    }
+
+    #[test]
+    fn nothing_is_drawn_before_the_first_snapshot() {
+        let buffer = SnapshotBuffer::new(millis(100));
+        assert!(buffer.positions(Instant::now(), &world_map(), &vec![]).is_empty());
+    }
+
+    #[test]
+    fn late_snapshots_are_dropped() {
+        let mut buffer = SnapshotBuffer::new(millis(100));
+        let start = buffer.started;
+        buffer.push(start, 1000, at(1.5, 1.5));
+        buffer.push(start + millis(100), 1100, at(2.5, 1.5));
+        buffer.push(start + millis(150), 1050, at(3.5, 1.5));
+        assert_eq!(buffer.snapshots.len(), 2);
+        assert_eq!(buffer.snapshots.back().unwrap().time, 1100);
+    }
+
+    #[test]
+    fn a_restarted_server_clock_starts_the_buffer_over() {
+        let mut buffer = SnapshotBuffer::new(millis(100));
+        let start = buffer.started;
+        buffer.push(start, 100_000, at(1.5, 1.5));
+        buffer.push(start + millis(100), 100_100, at(1.5, 1.5));
+        buffer.push(start + millis(200), 10, at(3.5, 1.5));
+        assert_eq!(buffer.snapshots.len(), 1);
+        assert_eq!(buffer.offset, Some(10 - 200));
+        buffer.push(start + millis(300), 110, at(3.5, 2.5));
+        assert_eq!(buffer.snapshots.len(), 2);
+        assert_eq!(buffer.positions(start + millis(400), &world_map(), &vec![])[0].1.y, 2.5);
+    }
+
+    #[test]
+    fn players_are_drawn_between_snapshots_delay_in_the_past() {
+        let mut buffer = SnapshotBuffer::new(millis(100));
+        let start = buffer.started;
+        buffer.push(start, 1000, at(1.5, 1.5));
+        buffer.push(start + millis(100), 1100, at(2.5, 1.5));
+        // server time 1050 once the delay is taken off
+        assert!((x(&buffer, start + millis(150)) - 2.0).abs() < 0.01);
+        // before the oldest snapshot, it is shown as it is
+        assert_eq!(x(&buffer, start + millis(50)), 1.5);
+    }
+
+    #[test]
+    fn players_jumping_far_are_not_interpolated() {
+        let mut buffer = SnapshotBuffer::new(millis(100));
+        let start = buffer.started;
+        buffer.push(start, 1000, at(1.5, 1.5));
+        buffer.push(start + millis(100), 1100, at(3.9, 3.5));
+        assert_eq!(x(&buffer, start + millis(150)), 3.9);
+    }
+
+    #[test]
+    fn players_keep_moving_a_little_without_news_but_not_through_walls() {
+        let mut buffer = SnapshotBuffer::new(millis(0));
+        let start = buffer.started;
+        buffer.push(start, 1000, at(1.5, 1.5));
+        buffer.push(start + millis(100), 1100, at(2.5, 1.5));
+        // 10 units per second for at most MAX_EXTRAPOLATION, stopped by the wall at x = 4
+        let x = x(&buffer, start + millis(1000));
+        assert!(x > 2.5 && x < 4.0, "{}", x);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::protocol::Lighting;
+
+/// How bright torches are on each frame of their animation
+const TORCH_FLICKER: [f32; 6] = [1.0, 0.85, 0.95, 0.8, 0.9, 0.75];
+/// Lights may make colors up to this much brighter than their texture
+const MAX_LIGHT: f32 = 2.0;
+
+/// Red, green and blue multipliers of every cell of the map
+pub struct LightMap {
+    ambient: f32,
+    cells: Vec<Vec<[f32; 3]>>,
+}
+
+impl LightMap {
+    /// Lights the cells of `world_map`, torches showing animation frame `torch_frame`
+    pub fn new(lighting: &Lighting, world_map: &Vec<Vec<u8>>, torch_frame: usize) -> LightMap {
+        let ambient = lighting.ambient.min(MAX_LIGHT);
+        let mut cells: Vec<Vec<[f32; 3]>> = world_map.iter().map(|row| vec![[ambient; 3]; row.len()]).collect();
+        for light in &lighting.lights {
+            let flicker = if light.torch { TORCH_FLICKER[torch_frame % TORCH_FLICKER.len()] } else { 1.0 };
+            for (x, row) in cells.iter_mut().enumerate() {
+                for (y, cell) in row.iter_mut().enumerate() {
+                    let dx = x as f32 + 0.5 - light.x;
+                    let dy = y as f32 + 0.5 - light.y;
+                    let falloff = 1.0 - (dx * dx + dy * dy).sqrt() / light.radius;
+                    if falloff <= 0.0 {
+                        continue;
+                    }
+                    for c in 0..3 {
+                        cell[c] = (cell[c] + light.color[c] as f32 / 255.0 * falloff * falloff * flicker).min(MAX_LIGHT);
+                    }
+                }
+            }
+        }
+        LightMap { ambient: ambient, cells: cells }
+    }
+
+    /// Multipliers of cell `x`, `y`, only the ambient light outside of the map
+    pub fn at(&self, x: i32, y: i32) -> [f32; 3] {
+        if x < 0 || y < 0 {
+            return [self.ambient; 3];
+        }
+        match self.cells.get(x as usize).and_then(|row| row.get(y as usize)) {
+            Some(light) => *light,
+            None => [self.ambient; 3],
+        }
+    }
+}
+
+/// `color` under `light`, which comes from `LightMap::at`
+pub fn apply(color: u32, light: [f32; 3]) -> u32 {
+    if light == [1.0; 3] {
+        return color;
+    }
+    let channel = |shift: u32, multiplier: f32| {
+        let value = (color >> shift & 0xff) as f32;
+        ((value * multiplier).min(255.0) as u32) << shift
+    };
+    channel(0, light[0]) | channel(8, light[1]) | channel(16, light[2])
+}
@@ -0,0 +1,38 @@
+use std::f32::consts::PI;
+use image::GenericImageView;
+
+/// Panoramic picture drawn where cells have no ceiling, its width covering a full turn
+/// and its height the upper half of the screen
+pub struct Sky {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Sky {
+    /// None for an empty picture
+    pub fn new(image: image::DynamicImage) -> Option<Sky> {
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return None;
+        }
+        Some(Sky { width: width as usize, height: height as usize, pixels: image.to_rgb().into_raw() })
+    }
+
+    /// Sky column seen through each of the `w` screen columns, which only depends on the camera angle
+    pub fn columns(&self, w: usize, dir_x: f32, dir_y: f32, plane_x: f32, plane_y: f32) -> Vec<usize> {
+        (0..w).map(|x| {
+            let camera_x = 2.0 * x as f32 / w as f32 - 1.0;
+            let angle = (dir_y + plane_y * camera_x).atan2(dir_x + plane_x * camera_x);
+            // screen columns go clockwise, so does the picture
+            ((-angle / (2.0 * PI)).rem_euclid(1.0) * self.width as f32) as usize % self.width
+        }).collect()
+    }
+
+    /// Color of sky column `column` on screen row `y`, `h` being the screen height
+    pub fn color(&self, column: usize, y: usize, h: usize) -> u32 {
+        let row = (y * self.height / (h / 2).max(1)).min(self.height - 1);
+        let i = (row * self.width + column) * 3;
+        self.pixels[i] as u32 | ((self.pixels[i + 1] as u32) << 8) | ((self.pixels[i + 2] as u32) << 16)
+    }
+}
@@ -1,35 +1,156 @@
 mod protocol;
+mod simulation;
 mod server;
 mod client;
 use crate::server::server;
+use crate::server::config::ServerConfig;
 use crate::client::client;
+use laminar::Socket;
 use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::thread;
+use std::time::Duration;
 #[macro_use] extern crate scan_fmt;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() == 5 {
-        let server_address = args[2].clone();
-        thread::spawn(move || {
-            server(server_address, true);
-        });
-        client(args[2].clone(), args[3].clone(), args[4].clone());
+/// how far in the past other players are shown, in milliseconds
+const DEFAULT_INTERPOLATION_DELAY: u64 = 200;
+/// any free port will do for the client
+const DEFAULT_CLIENT_BIND: &str = "0.0.0.0:0";
+/// flags read by `ServerConfig::from_args`, a trailing `=` meaning the flag takes a value
+const SERVER_FLAGS: [&str; 8] = ["--config=", "--bind=", "--level=", "--rules=", "--max-players=", "--max-players-per-address=", "--log-level=", "--silent"];
+/// flags read by `ClientOptions::from_args`
+const CLIENT_FLAGS: [&str; 5] = ["--bind=", "--input", "--interpolation-delay=", "--skin=", "--spectate"];
+
+/// How the client plays, from its command line flags
+struct ClientOptions {
+    bind: String,
+    /// let the server simulate movement from our inputs
+    input_networking: bool,
+    /// trade latency for smoother remote players
+    interpolation_delay: Duration,
+    /// how other players see us
+    skin: u8,
+    /// watch the others without playing
+    spectate: bool,
+}
+
+impl ClientOptions {
+    fn from_args(args: &[String]) -> Result<ClientOptions, String> {
+        let mut options = ClientOptions {
+            bind: String::from(DEFAULT_CLIENT_BIND),
+            input_networking: false,
+            interpolation_delay: Duration::from_millis(DEFAULT_INTERPOLATION_DELAY),
+            skin: 0,
+            spectate: false,
+        };
+        for arg in args {
+            if let Some(address) = arg.strip_prefix("--bind=") {
+                options.bind = String::from(address);
+            }
+            else if let Some(ms) = arg.strip_prefix("--interpolation-delay=") {
+                options.interpolation_delay = Duration::from_millis(ms.parse().map_err(|_| format!("--interpolation-delay expects milliseconds, not {}", ms))?);
+            }
+            else if let Some(n) = arg.strip_prefix("--skin=") {
+                options.skin = n.parse().map_err(|_| format!("--skin expects a number, not {}", n))?;
+            }
+            else if arg == "--input" {
+                options.input_networking = true;
+            }
+            else if arg == "--spectate" {
+                options.spectate = true;
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn usage() {
+    println!("usage");
+    println!("        server: server [--bind=<address>] [--level=<level>[,<level>...]] [--rules=<file>] [--max-players=<n>]");
+    println!("                       [--max-players-per-address=<n>] [--log-level=quiet|info|debug] [--silent] [--config=<file>]");
+    println!("           e.g: server --bind=0.0.0.0:12345 --level=levels/metro.json,levels/spyral.json");
+    println!("        client: client <server address> <nickname> [--bind=<address>] [--input] [--interpolation-delay=<ms>] [--skin=<n>] [--spectate]");
+    println!("           e.g: client 127.0.0.1:12345 yazgoo");
+    println!(" client+server: serve <nickname> [server flags but --bind=<address> is the server's] [client flags]");
+    println!("           e.g: serve yazgoo --level=levels/metro.json");
+}
+
+/// Prints why we cannot go on, and stops there
+fn fail(message: &str) -> ! {
+    println!("{}", message);
+    std::process::exit(1);
+}
+
+/// Checks every flag is one of `known`, so that typos do not go unnoticed
+fn check_flags(flags: &[String], known: &[&str]) {
+    for flag in flags {
+        let recognized = known.iter().any(|name| if name.ends_with('=') { flag.starts_with(name) } else { flag == name });
+        if !recognized {
+            usage();
+            fail(&format!("unknown flag {}", flag));
+        }
     }
-    else if args.len() == 4 {
-        client(args[1].clone(), args[2].clone(), args[3].clone());
+}
+
+/// Reads `address`, which may use a host name, for the `what` side
+fn resolve(what: &str, address: &str) -> SocketAddr {
+    match address.to_socket_addrs().map(|mut addresses| addresses.next()) {
+        Ok(Some(address)) => address,
+        Ok(None) => fail(&format!("{} address {} resolves to nothing", what, address)),
+        Err(e) => fail(&format!("bad {} address {}, expected <ip or host>:<port>: {}", what, address, e)),
     }
-    else if args.len() == 2 {
-        server(args[1].clone(), false);
+}
+
+/// Listens on `address` for the `what` side
+fn bind(what: &str, address: SocketAddr) -> Socket {
+    match Socket::bind(address) {
+        Ok(socket) => socket,
+        Err(e) => fail(&format!("cannot listen on {} for the {}: {}", address, what, e)),
     }
-    else {
-        println!("usage");
-        println!("        server: <server address>");
-        println!("           e.g:  0.0.0.0:12345");
-        println!("        client: <server address> <client address> <nickname>");
-        println!("           e.g:  0.0.0.0:12345    0.0.0.0:12346    yazgoo");
-        println!(" client+server: serve <server address> <client address> <nickname>");
-        println!("           e.g: serve  0.0.0.0:12345    0.0.0.0:12346    yazgoo");
+}
+
+/// Where to reach a server listening on `address` from this machine: replies from
+/// a server bound to all interfaces come from a real address, which is what the client expects
+fn local(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), address.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), address.port()),
+        _ => address,
+    }
+}
+
+fn main() {
+    let (flags, args): (Vec<String>, Vec<String>) = env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["server"] => {
+            check_flags(&flags, &SERVER_FLAGS);
+            let config = ServerConfig::from_args(&flags).unwrap_or_else(|e| fail(&e));
+            let socket = bind("server", resolve("server", &config.bind));
+            server(socket, config, false);
+        },
+        ["client", server_address, nickname] => {
+            check_flags(&flags, &CLIENT_FLAGS);
+            let options = ClientOptions::from_args(&flags).unwrap_or_else(|e| fail(&e));
+            let server_address = local(resolve("server", server_address));
+            let socket = bind("client", resolve("client", &options.bind));
+            client(server_address, socket, nickname.to_string(), options.skin, options.input_networking, options.interpolation_delay, options.spectate);
+        },
+        ["serve", nickname] => {
+            let known: Vec<&str> = SERVER_FLAGS.iter().chain(CLIENT_FLAGS.iter().filter(|flag| **flag != "--bind=")).cloned().collect();
+            check_flags(&flags, &known);
+            let config = ServerConfig::from_args(&flags).unwrap_or_else(|e| fail(&e));
+            let options = ClientOptions::from_args(&flags).unwrap_or_else(|e| fail(&e));
+            let server_address = resolve("server", &config.bind);
+            // both sockets are bound before the client takes over the terminal, so errors can be read
+            let server_socket = bind("server", server_address);
+            let socket = bind("client", resolve("client", DEFAULT_CLIENT_BIND));
+            thread::spawn(move || {
+                server(server_socket, config, true);
+            });
+            client(local(server_address), socket, nickname.to_string(), options.skin, options.input_networking, options.interpolation_delay, options.spectate);
+        },
+        _ => usage(),
     }
 }
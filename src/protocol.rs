@@ -1,7 +1,58 @@
 use serde::{Serialize, Deserialize};
+use bincode::Options;
 use std::collections::HashMap;
 use std::time::Duration;
-use std::net::SocketAddr;
+
+/// Bumped on every change to the messages below.
+/// The handshake variants must keep their place in both enums and start with their `Handshake`,
+/// so that any client and server can tell each other they do not match, see `decode_handshake`.
+pub const PROTOCOL_VERSION: u32 = 18;
+
+/// the client lets players vote for the next level
+pub const CAPABILITY_VOTE: u32 = 1;
+/// the client sends its inputs instead of its position, the server simulates its movement
+pub const CAPABILITY_INPUT: u32 = 1 << 1;
+/// the client only watches: it is not shown to others, picks no coins and fires no triggers.
+/// Only offered by clients joining as spectators.
+pub const CAPABILITY_SPECTATE: u32 = 1 << 2;
+
+pub const CAPABILITIES: u32 = CAPABILITY_VOTE | CAPABILITY_INPUT | CAPABILITY_SPECTATE;
+
+/// Longest chat message, in characters
+pub const MAX_CHAT_LENGTH: usize = 100;
+
+/// Given by the server at hello time, stays the same when the player's address changes
+pub type PlayerId = u32;
+
+/// Largest message we accept, so that a forged length prefix cannot make us allocate gigabytes
+pub const MAX_MESSAGE_SIZE: u64 = 1 << 20;
+
+/// Same encoding as `bincode::deserialize`, but bounded by `MAX_MESSAGE_SIZE`
+pub fn decode<'a, T: Deserialize<'a>>(payload: &'a [u8]) -> bincode::Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MESSAGE_SIZE)
+        .deserialize(payload)
+}
+
+/// Variant indexes of `ClientMessage::MessageHello` and `ClientMessage::MessageReconnect`
+const HANDSHAKE_VARIANTS: [u32; 2] = [0, 7];
+
+/// Reads only the handshake starting a hello or a reconnect, for clients speaking
+/// another version whose other fields we cannot decode
+pub fn decode_handshake(payload: &[u8]) -> Option<Handshake> {
+    match decode::<(u32, Handshake)>(payload) {
+        Ok((variant, handshake)) if HANDSHAKE_VARIANTS.contains(&variant) => Some(handshake),
+        _ => None,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub capabilities: u32,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Position {
@@ -12,22 +63,299 @@ pub struct Position {
     pub speed: f32
 }
 
+/// Another player as seen by us
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemotePlayer {
+    pub nickname: String,
+    pub skin: u8,
+    pub position: Position,
+}
+
+/// A line of the scoreboard
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScoreLine {
+    pub nickname: String,
+    /// coins picked this round
+    pub coins: u32,
+    /// rounds won on this server, ever
+    pub rounds_won: u32,
+}
+
+/// Distance fog of a level, which also shades far away things
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Fog {
+    /// red, green and blue
+    pub color: [u8; 3],
+    /// how fast colors fade into the fog with distance, 0 for no fog
+    pub density: f32,
+}
+
+/// A light source of a level
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Light {
+    pub x: f32,
+    pub y: f32,
+    /// red, green and blue
+    pub color: [u8; 3],
+    /// distance in cells at which the light is gone
+    pub radius: f32,
+    /// a torch is drawn there, and the light flickers with its flames
+    #[serde(default)]
+    pub torch: bool,
+}
+
+/// A sliding door, drawn half a cell back from the walls around it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Door {
+    pub x: u16,
+    pub y: u16,
+    /// how far it has slid, from 0 for shut to 1 for open
+    pub open: f32,
+    /// sliding open, or else shut
+    pub opening: bool,
+}
+
+/// How a level is lit
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lighting {
+    /// brightness of cells no light reaches, 1 keeps textures as they are
+    pub ambient: f32,
+    #[serde(default)]
+    pub lights: Vec<Light>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Movement {
+    WalkForward,
+    WalkBackward,
+    StrafeLeft,
+    StrafeRight,
+    RotateRight,
+    RotateLeft,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum InputCommand {
+    Move(Movement),
+    TakePortal(u8),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerMessage {
-    MessagePositions(HashMap<SocketAddr, Position>),
+    /// server version and the capabilities both sides support
+    MessageWelcome(Handshake),
+    /// server version and the reason why the hello was refused
+    MessageRejected(Handshake, String),
+    /// server time in milliseconds and the other players positions at that time
+    MessagePositions(u64, HashMap<PlayerId, RemotePlayer>),
     MessageWorldMap(Vec<Vec<u8>>),
     MessageWorldLayer(Vec<Vec<u8>>),
     MessageSprites(Vec<Vec<f32>>),
-    MessageTexturesZip(String),
+    /// texture pack url, and the name of the sky picture in it if the level has one
+    MessageTexturesZip(String, Option<String>),
     MessageGoldCoins(Vec<(f32, f32)>),
     MessagePortals(Vec<Vec<f32>>, Vec<Vec<f32>>),
     MessageText(String, Duration),
     MessageTeleport(Position),
+    /// the server dropped us, with the reason
+    MessageKicked(String),
+    /// authoritative position after the last processed input tick, in input mode
+    MessageSnapshot(u32, Position),
+    /// cells whose wall or layer changed: x, y, world_map value, world_layer value
+    MessageTileChanges(Vec<(u16, u16, u8, u8)>),
+    /// nickname of a player who just arrived
+    MessagePlayerJoined(String),
+    /// nickname of a player who is gone, and why
+    MessagePlayerLeft(String, String),
+    /// our player id and the token to give back when reconnecting, sent after the welcome
+    MessageIdentity(PlayerId, u64),
+    /// players still in the game, best first
+    MessageScoreboard(Vec<ScoreLine>),
+    /// nickname and what they said
+    MessageChat(String, String),
+    /// floor texture + 1 of every cell, 0 for none
+    MessageFloor(Vec<Vec<u8>>),
+    /// ceiling texture + 1 of every cell, 0 for open sky
+    MessageCeiling(Vec<Vec<u8>>),
+    MessageFog(Fog),
+    MessageLighting(Lighting),
+    /// state of every door of the level, which then keep sliding on their own
+    MessageDoors(Vec<Door>),
+    /// names of the levels one can vote for, the first one being voted for with 1
+    MessagePlaylist(Vec<String>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ClientMessage {
+    /// client version, capabilities, nickname and skin
+    MessageHello(Handshake, String, u8),
     MessagePosition(Position),
-    MessageHello(String),
     MessageAction(f32, f32, u8),
+    MessageVote(u8),
+    /// input command for a tick, in input mode
+    MessageInput(u32, InputCommand),
+    /// asks for the whole world_map and world_layer again
+    MessageResync,
+    /// the player quits
+    MessageBye,
+    /// same as a hello, for a player coming back with the token the server gave them
+    MessageReconnect(Handshake, String, u8, u64),
+    /// something to say to everyone
+    MessageChat(String),
+    /// password and admin command to run, answered with texts
+    MessageRcon(String, String),
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use rand::rngs::StdRng;
+
+    fn handshake(protocol_version: u32) -> Handshake {
+        Handshake { protocol_version: protocol_version, capabilities: CAPABILITIES }
+    }
+
+    fn position() -> Position {
+        Position { x: 1.5, y: 2.5, dir_x: -1.0, dir_y: 0.0, speed: 0.0 }
+    }
+
+    fn client_messages() -> Vec<Vec<u8>> {
+        vec![
+            ClientMessage::MessageHello(handshake(PROTOCOL_VERSION), String::from("nickname"), 1),
+            ClientMessage::MessagePosition(position()),
+            ClientMessage::MessageAction(1.5, 2.5, 0),
+            ClientMessage::MessageVote(1),
+            ClientMessage::MessageInput(3, InputCommand::Move(Movement::WalkForward)),
+            ClientMessage::MessageReconnect(handshake(PROTOCOL_VERSION), String::from("nickname"), 1, 42),
+            ClientMessage::MessageChat(String::from("hello")),
+            ClientMessage::MessageRcon(String::from("password"), String::from("players")),
+        ].iter().map(|message| bincode::serialize(message).unwrap()).collect()
+    }
+
+    fn server_messages() -> Vec<Vec<u8>> {
+        let mut positions = HashMap::new();
+        positions.insert(1, RemotePlayer { nickname: String::from("nickname"), skin: 1, position: position() });
+        vec![
+            ServerMessage::MessageRejected(handshake(PROTOCOL_VERSION), String::from("banned")),
+            ServerMessage::MessagePositions(10, positions),
+            ServerMessage::MessageWorldMap(vec![vec![1, 1], vec![1, 0]]),
+            ServerMessage::MessageTexturesZip(String::from("url"), Some(String::from("sky.png"))),
+            ServerMessage::MessageText(String::from("hello"), Duration::from_secs(1)),
+            ServerMessage::MessageScoreboard(vec![ScoreLine { nickname: String::from("nickname"), coins: 1, rounds_won: 2 }]),
+            ServerMessage::MessageLighting(Lighting { ambient: 1.0, lights: vec![Light { x: 1.5, y: 1.5, color: [255, 0, 0], radius: 2.0, torch: true }] }),
+            ServerMessage::MessageDoors(vec![Door { x: 1, y: 1, open: 0.5, opening: true }]),
+            ServerMessage::MessagePlaylist(vec![String::from("metro"), String::from("rat_race")]),
+        ].iter().map(|message| bincode::serialize(message).unwrap()).collect()
+    }
+
+    /// Every message cut short
+    fn truncated(messages: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        messages.iter().flat_map(|message| (0..message.len()).map(move |length| message[..length].to_vec())).collect()
+    }
+
+    /// `prefix` followed by lengths far beyond what we accept
+    fn oversized(prefix: &[u8]) -> Vec<Vec<u8>> {
+        [MAX_MESSAGE_SIZE + 1, 1 << 40, u64::MAX].iter().map(|length| {
+            let mut payload = prefix.to_vec();
+            payload.extend_from_slice(&length.to_le_bytes());
+            payload
+        }).collect()
+    }
+
+    fn oversized_client_messages() -> Vec<Vec<u8>> {
+        let chat = bincode::serialize(&ClientMessage::MessageChat(String::new())).unwrap();
+        let hello = bincode::serialize(&ClientMessage::MessageHello(handshake(PROTOCOL_VERSION), String::new(), 0)).unwrap();
+        // variant, then the length of the string
+        let mut payloads = oversized(&chat[..4]);
+        // variant and handshake, then the length of the nickname
+        payloads.extend(oversized(&hello[..12]));
+        payloads
+    }
+
+    fn oversized_server_messages() -> Vec<Vec<u8>> {
+        let world_map = bincode::serialize(&ServerMessage::MessageWorldMap(vec![vec![]])).unwrap();
+        let positions = bincode::serialize(&ServerMessage::MessagePositions(0, HashMap::new())).unwrap();
+        let playlist = bincode::serialize(&ServerMessage::MessagePlaylist(vec![])).unwrap();
+        // variant then the length of the map, then the length of its first row
+        let mut payloads = oversized(&world_map[..4]);
+        payloads.extend(oversized(&world_map[..12]));
+        // variant and time, then the number of players
+        payloads.extend(oversized(&positions[..12]));
+        payloads.extend(oversized(&playlist[..4]));
+        payloads
+    }
+
+    /// Random bytes, some starting with a variant index so that they get past it
+    fn random_payloads() -> Vec<Vec<u8>> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..2000).map(|i| {
+            let length = rng.gen_range(0, 64);
+            let mut payload: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            if i % 2 == 0 && payload.len() >= 4 {
+                payload[..4].copy_from_slice(&rng.gen_range(0u32, 30).to_le_bytes());
+            }
+            payload
+        }).collect()
+    }
+
+    /// Packets no client would send, for the server to survive
+    pub fn malformed_client_payloads() -> Vec<Vec<u8>> {
+        let mut payloads = truncated(client_messages());
+        payloads.extend(oversized_client_messages());
+        payloads.extend(random_payloads());
+        payloads
+    }
+
+    #[test]
+    fn truncated_messages_are_errors() {
+        for payload in truncated(client_messages()) {
+            assert!(decode::<ClientMessage>(&payload).is_err(), "{:?}", payload);
+            // the handshake of a hello may be whole, but not if fewer bytes than it arrived
+            if payload.len() < 12 {
+                assert!(decode_handshake(&payload).is_none(), "{:?}", payload);
+            }
+        }
+        for payload in truncated(server_messages()) {
+            assert!(decode::<ServerMessage>(&payload).is_err(), "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn oversized_lengths_are_errors() {
+        for payload in oversized_client_messages() {
+            assert!(decode::<ClientMessage>(&payload).is_err(), "{:?}", payload);
+        }
+        for payload in oversized_server_messages() {
+            assert!(decode::<ServerMessage>(&payload).is_err(), "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn malformed_payloads_do_not_panic() {
+        let payloads = malformed_client_payloads().into_iter()
+            .chain(truncated(server_messages()))
+            .chain(oversized_server_messages());
+        for payload in payloads {
+            let _ = decode::<ClientMessage>(&payload);
+            let _ = decode::<ServerMessage>(&payload);
+            let _ = decode_handshake(&payload);
+        }
+    }
+
+    #[test]
+    fn handshakes_are_read_from_hellos_and_reconnects() {
+        let hello = bincode::serialize(&ClientMessage::MessageHello(handshake(PROTOCOL_VERSION + 1), String::from("a"), 0)).unwrap();
+        let reconnect = bincode::serialize(&ClientMessage::MessageReconnect(handshake(PROTOCOL_VERSION + 1), String::from("a"), 0, 42)).unwrap();
+        assert_eq!(decode_handshake(&hello).map(|h| h.protocol_version), Some(PROTOCOL_VERSION + 1));
+        assert_eq!(decode_handshake(&reconnect).map(|h| h.protocol_version), Some(PROTOCOL_VERSION + 1));
+    }
+
+    #[test]
+    fn other_messages_have_no_handshake() {
+        let bye = bincode::serialize(&ClientMessage::MessageBye).unwrap();
+        let vote = bincode::serialize(&ClientMessage::MessageVote(1)).unwrap();
+        assert!(decode_handshake(&bye).is_none());
+        assert!(decode_handshake(&vote).is_none());
+    }
 }
@@ -1,188 +1,703 @@
-extern crate rand;
-
-use std::net::SocketAddr;
-use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, HashSet};
+use std::env;
 use laminar::{Socket, SocketEvent, Packet};
 use crate::protocol::*;
 use std::time::{Duration, Instant};
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Sender, RecvTimeoutError};
 use std::thread;
-use rand::prelude::*;
+use std::path::Path;
 mod levels;
+mod triggers;
+mod rotation;
+mod round;
+mod movement;
+mod players;
+mod leaderboard;
+mod admin;
+pub mod config;
+use crate::server::round::{GameRules, Round, RoundEvent, random_position};
+use crate::server::players::{InputState, Players, PlayerState};
+use crate::server::leaderboard::Leaderboard;
+use crate::server::admin::Command;
+use crate::server::config::{LogLevel, ServerConfig};
+use crate::simulation::{self, Camera};
 
-fn check_gold_coins(coins_found: u32, world_map: &Vec<Vec<u8>>, packet_sender: &Sender<Packet>, gold_coins: &mut Vec<(f32, f32)>, positions : &HashMap<SocketAddr, Position>, nicknames: &HashMap<SocketAddr, String>, points: &mut HashMap<SocketAddr, u8>) -> u32 {
-    let mut new_coins_found = coins_found;
-    let mut who = None;
-    for (key, value) in positions {
-        for i in 0..gold_coins.len() {
-            if (value.x as i32, value.y as i32) == (gold_coins[i].0 as i32, gold_coins[i].1 as i32) {
-                let (new_x, new_y) = random_position(world_map);
-                gold_coins[i].0 = new_x;
-                gold_coins[i].1 = new_y;
-                who = Some(key);
-                points.entry(*key).and_modify(|e| { *e += 1 });
-            }
+const LEADERBOARD_FILE: &str = "leaderboard.json";
+/// environment variable holding the remote admin password, remote commands are refused without it
+const RCON_PASSWORD_VARIABLE: &str = "RCON_PASSWORD";
+/// malformed packets tolerated from an endpoint before it is kicked
+const MAX_DECODE_ERRORS: u32 = 10;
+/// how long packets from a kicked endpoint are ignored, after which the address may well be someone else's
+const KICKED_FOR: Duration = Duration::from_secs(5 * 60);
+/// players not heard of for this long are dropped
+const STALE_AFTER: Duration = Duration::from_secs(20);
+/// time between two snapshots sent to the same client
+const SNAPSHOT_INTERVAL: Duration = Duration::from_millis(100);
+
+fn send(packet_sender: &Sender<Packet>, endpoint: SocketAddr, message: &ServerMessage) {
+    let message_ser = bincode::serialize(message).unwrap();
+    if let Err(e) = packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)) {
+        println!("could not send to {}: {}", endpoint, e);
+    }
+}
+
+fn broadcast(packet_sender: &Sender<Packet>, players: &Players, message: &ServerMessage) {
+    for endpoint in players.addresses() {
+        send(packet_sender, *endpoint, message);
+    }
+}
+
+fn load_rules(path: &str, world_map: &Vec<Vec<u8>>) -> GameRules {
+    if !Path::new(path).exists() {
+        return GameRules::default();
+    }
+    match GameRules::load(path, world_map) {
+        Ok(rules) => rules,
+        Err(e) => {
+            println!("could not load rules: {}", e);
+            std::process::exit(1);
         }
     }
-    if let Some(winner_endpoint) = who {
-        new_coins_found += 1;
-        if new_coins_found > 2 {
-            let final_winner_endpoint = points.iter().max_by_key(|entry | entry.1).unwrap();
-            let winner = nicknames.get(final_winner_endpoint.0).unwrap();
-            for (key, _) in positions {
-                let textures_message = ServerMessage::MessageText(format!("winner: {}", winner), Duration::from_secs(10));
-                let message_ser = bincode::serialize(&textures_message).unwrap();
-                packet_sender.send(Packet::reliable_unordered(key.clone(), message_ser)).unwrap();
-                points.insert(*key, 0);
-            }
-            new_coins_found = 0;
+}
+
+fn scoreboard(round: &Round, players: &Players, leaderboard: &Leaderboard) -> Vec<ScoreLine> {
+    let mut lines: Vec<ScoreLine> = round.points.iter()
+        .filter_map(|(id, points)| players.state(*id).map(|state| ScoreLine {
+            nickname: state.nickname.clone(),
+            coins: *points,
+            rounds_won: leaderboard.rounds_won(&state.nickname),
+        }))
+        .collect();
+    lines.sort_by(|a, b| b.coins.cmp(&a.coins).then(b.rounds_won.cmp(&a.rounds_won)).then(a.nickname.cmp(&b.nickname)));
+    lines
+}
+
+/// Tells everyone about picked coins and winners, returns true if the round is over
+fn announce(events: Vec<RoundEvent>, round: &Round, packet_sender: &Sender<Packet>, players: &Players) -> bool {
+    let duration = Duration::from_secs(round.rules.announcement_duration);
+    let mut round_over = false;
+    let mut coins_changed = false;
+    for event in events {
+        match event {
+            RoundEvent::CoinFound { who, coins_found } => {
+                coins_changed = true;
+                broadcast(packet_sender, players, &ServerMessage::MessageText(format!("coin {}: {}", coins_found, players.nickname(who)), duration));
+            },
+            RoundEvent::CoinRespawned => {
+                coins_changed = true;
+            },
+            RoundEvent::RoundOver { winner } => {
+                round_over = true;
+                let text = match winner {
+                    Some(winner) => format!("winner: {}", players.nickname(winner)),
+                    None => String::from("no winner"),
+                };
+                broadcast(packet_sender, players, &ServerMessage::MessageText(text, duration));
+            },
+        }
+    }
+    if coins_changed && !round_over {
+        broadcast(packet_sender, players, &ServerMessage::MessageGoldCoins(round.gold_coins.clone()));
+    }
+    round_over
+}
+
+/// Whether player `id` joined to watch the others
+fn spectating(players: &Players, id: PlayerId) -> bool {
+    players.state(id).map_or(false, |state| state.spectating())
+}
+
+/// Forgets everything about a player and tells the others why they are gone
+fn remove_player(id: PlayerId, reason: &str, packet_sender: &Sender<Packet>, players: &mut Players, round: &mut Round, rotation: &mut rotation::Rotation, silent: bool) {
+    round.leave(id);
+    rotation.forget(id);
+    if let Some(state) = players.leave(id) {
+        if !silent {
+            println!("{} (player {}) {}", state.nickname, id, reason);
         }
-        else {
-            for (key, _) in positions {
-                let textures_message = ServerMessage::MessageText(format!("coin {}: {}", new_coins_found, nicknames.get(winner_endpoint).unwrap()), Duration::from_secs(10));
-                let message_ser = bincode::serialize(&textures_message).unwrap();
-                packet_sender.send(Packet::reliable_unordered(key.clone(), message_ser)).unwrap();
+        broadcast(packet_sender, players, &ServerMessage::MessagePlayerLeft(state.nickname, String::from(reason)));
+    }
+}
+
+/// Switches everyone, spectators included, to `next_level` and starts a new round on it
+fn start_level(next_level: levels::Level, level: &mut levels::Level, open_doors: &mut Vec<triggers::OpenDoor>, round: &mut Round, packet_sender: &Sender<Packet>, players: &mut Players) {
+    *level = next_level;
+    *open_doors = vec![];
+    if let Err(e) = round.rules.validate(&level.world_map) {
+        println!("the rules do not fit this level, placing fewer coins: {}", e);
+    }
+    round.restart(&level.world_map);
+    let endpoints: Vec<(PlayerId, SocketAddr)> = players.states().filter_map(|(key, _)| players.address(*key).map(|endpoint| (*key, endpoint))).collect();
+    for (key, endpoint) in endpoints {
+        let spawn = send_level(packet_sender, endpoint, level, &round.gold_coins);
+        if let Some(state) = players.state_mut(key) {
+            if state.position.is_some() {
+                state.position = Some(spawn);
+                state.moved_at = Instant::now();
             }
         }
-        for (key, _) in positions {
-            let message = ServerMessage::MessageGoldCoins(gold_coins.clone());
-            let message_ser = bincode::serialize(&message).unwrap();
-            packet_sender.send(Packet::reliable_unordered(key.clone(), message_ser)).unwrap();
+    }
+}
+
+/// Players who can vote for the next level
+fn voters<'a>(players: &'a Players) -> impl Iterator<Item = SocketAddr> + 'a {
+    players.states()
+        .filter(|(_, state)| state.capabilities & CAPABILITY_VOTE != 0)
+        .filter_map(move |(key, _)| players.address(*key))
+}
+
+/// Tells voters which levels they can vote for, votes having just opened
+fn announce_playlist(packet_sender: &Sender<Packet>, players: &Players, rotation: &rotation::Rotation) {
+    for endpoint in voters(players) {
+        send(packet_sender, endpoint, &ServerMessage::MessagePlaylist(rotation.names()));
+    }
+}
+
+/// Cells that differ between two versions of the world, which must have the same size
+fn tile_changes(old_map: &Vec<Vec<u8>>, old_layer: &Vec<Vec<u8>>, world_map: &Vec<Vec<u8>>, world_layer: &Vec<Vec<u8>>) -> Vec<(u16, u16, u8, u8)> {
+    let mut changes = vec![];
+    for x in 0..world_map.len() {
+        for y in 0..world_map[x].len() {
+            if old_map[x][y] != world_map[x][y] || old_layer[x][y] != world_layer[x][y] {
+                changes.push((x as u16, y as u16, world_map[x][y], world_layer[x][y]));
+            }
         }
     }
-    new_coins_found
+    changes
 }
 
-fn random_position(world_map: &Vec<Vec<u8>>) -> (f32, f32) {
-    let mut rng = rand::thread_rng();
-    loop {
-        let y = rng.gen_range(0, 23);
-        let x = rng.gen_range(0, 23);
-        if world_map[x][y] == 0 {
-            let coin_position = (x as f32 + 0.5, y as f32 + 0.5);
-            return coin_position;
+/// Sends the whole level and teleports the player to a random place, returns that place
+fn send_level(packet_sender: &Sender<Packet>, endpoint: SocketAddr, level: &levels::Level, gold_coins: &Vec<(f32, f32)>) -> Position {
+    let (x, y) = random_position(&level.world_map);
+    let position = Position { x: x, y: y, dir_x: -1.0, dir_y: 0.0, speed: 0.0 };
+    send(packet_sender, endpoint, &ServerMessage::MessageTeleport(position.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessageWorldMap(level.world_map.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessageWorldLayer(level.world_layer.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessageFloor(level.floor.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessageCeiling(level.ceiling.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessageFog(level.fog.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessageLighting(level.lighting.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessageDoors(level.doors.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessageSprites(level.sprites.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessageTexturesZip(level.url.clone(), level.sky.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessageGoldCoins(gold_coins.clone()));
+    send(packet_sender, endpoint, &ServerMessage::MessagePortals(level.portals.clone(), level.portals_destinations.clone()));
+    position
+}
+
+/// Applies an input to the simulated player, returns its new position
+fn simulate(command: InputCommand, position: &Position, level: &levels::Level) -> Position {
+    let mut camera = Camera::from_position(position);
+    let speed = simulation::run(command, &level.world_map, &level.doors, &level.portals, &level.portals_destinations, &mut camera);
+    camera.to_position(speed)
+}
+
+/// Everything the server knows about the game being played
+struct Game {
+    packet_sender: Sender<Packet>,
+    config: ServerConfig,
+    silent: bool,
+    debug: bool,
+    level: levels::Level,
+    rotation: rotation::Rotation,
+    round: Round,
+    players: Players,
+    decode_errors: HashMap<SocketAddr, u32>,
+    /// kicked endpoints, ignored until the given time
+    kicked: HashMap<SocketAddr, Instant>,
+    banned_addresses: HashSet<IpAddr>,
+    banned_nicknames: HashSet<String>,
+    rcon_password: Option<String>,
+    /// admin commands to run, with who to answer: nobody for the console
+    commands: Vec<(String, Option<SocketAddr>)>,
+    open_doors: Vec<triggers::OpenDoor>,
+    /// last scoreboard broadcast, also sent to whoever joins
+    scores: Vec<ScoreLine>,
+    /// positions are timestamped from here so that clients can interpolate them
+    started: Instant,
+}
+
+impl Game {
+    fn new(packet_sender: Sender<Packet>, config: ServerConfig, rotation: rotation::Rotation, level: levels::Level, rules: GameRules, silent: bool) -> Game {
+        let debug = !silent && config.log_level == LogLevel::Debug;
+        let round = Round::new(rules, &level.world_map);
+        Game {
+            packet_sender: packet_sender,
+            config: config,
+            silent: silent,
+            debug: debug,
+            level: level,
+            rotation: rotation,
+            round: round,
+            players: Players::new(),
+            decode_errors: HashMap::new(),
+            kicked: HashMap::new(),
+            banned_addresses: HashSet::new(),
+            banned_nicknames: HashSet::new(),
+            rcon_password: env::var(RCON_PASSWORD_VARIABLE).ok().filter(|password| !password.is_empty()),
+            commands: vec![],
+            open_doors: vec![],
+            scores: vec![],
+            started: Instant::now(),
         }
     }
 }
 
+/// Handles a packet from `endpoint`, whatever it holds
+fn dispatch(game: &mut Game, endpoint: SocketAddr, received_data: &[u8]) {
+    if game.kicked.get(&endpoint).map_or(false, |until| *until > Instant::now()) {
+        return;
+    }
+    let message = match decode::<ClientMessage>(received_data) {
+        Ok(message) => message,
+        Err(e) => {
+            // not a packet we can read, but maybe a hello from another version
+            if let Some(handshake) = decode_handshake(received_data) {
+                if handshake.protocol_version != PROTOCOL_VERSION {
+                    let server_handshake = Handshake { protocol_version: PROTOCOL_VERSION, capabilities: CAPABILITIES };
+                    let reason = format!("protocol version {} is not supported, server uses {}", handshake.protocol_version, PROTOCOL_VERSION);
+                    send(&game.packet_sender, endpoint, &ServerMessage::MessageRejected(server_handshake, reason));
+                    return;
+                }
+            }
+            let id = match game.players.id(&endpoint) {
+                Some(id) => id,
+                // strangers get no state at all, whatever they send
+                None => return,
+            };
+            let errors = game.decode_errors.entry(endpoint).or_insert(0);
+            *errors += 1;
+            if !game.silent {
+                println!("malformed packet from {} ({} so far): {}", endpoint, errors, e);
+            }
+            if *errors >= MAX_DECODE_ERRORS {
+                if !game.silent {
+                    println!("kicking {}", endpoint);
+                }
+                send(&game.packet_sender, endpoint, &ServerMessage::MessageKicked(String::from("too many malformed packets")));
+                remove_player(id, "was kicked", &game.packet_sender, &mut game.players, &mut game.round, &mut game.rotation, game.silent);
+                game.decode_errors.remove(&endpoint);
+                game.kicked.insert(endpoint, Instant::now() + KICKED_FOR);
+            }
+            return;
+        }
+    };
+    // a reconnect is a hello from someone we may already know
+    let (message, token) = match message {
+        ClientMessage::MessageReconnect(handshake, nickname, skin, token) => (ClientMessage::MessageHello(handshake, nickname, skin), Some(token)),
+        message => (message, None),
+    };
+    match (message, game.players.id(&endpoint)) {
+        (ClientMessage::MessageHello(handshake, nickname, skin), _) => {
+            let server_handshake = Handshake { protocol_version: PROTOCOL_VERSION, capabilities: CAPABILITIES };
+            if handshake.protocol_version != PROTOCOL_VERSION {
+                let reason = format!("protocol version {} is not supported, server uses {}", handshake.protocol_version, PROTOCOL_VERSION);
+                send(&game.packet_sender, endpoint, &ServerMessage::MessageRejected(server_handshake, reason));
+                return;
+            }
+            if game.banned_addresses.contains(&endpoint.ip()) || game.banned_nicknames.contains(&nickname) {
+                send(&game.packet_sender, endpoint, &ServerMessage::MessageRejected(server_handshake, String::from("banned")));
+                return;
+            }
+            let playing = game.players.lookup(&endpoint, token).map_or(false, |id| game.players.state(id).is_some());
+            if !playing && game.config.max_players.map_or(false, |max| game.players.count() >= max) {
+                if !game.silent {
+                    println!("{} turned away, the server is full", nickname);
+                }
+                let reason = format!("the server is full, {} players at most", game.config.max_players.unwrap_or(0));
+                send(&game.packet_sender, endpoint, &ServerMessage::MessageRejected(server_handshake, reason));
+                return;
+            }
+            let from_address = game.players.addresses().filter(|address| address.ip() == endpoint.ip()).count();
+            if !playing && game.config.max_players_per_address.map_or(false, |max| from_address >= max) {
+                if !game.silent {
+                    println!("{} turned away, {} players already come from {}", nickname, from_address, endpoint.ip());
+                }
+                let reason = format!("too many players from your address, {} at most", game.config.max_players_per_address.unwrap_or(0));
+                send(&game.packet_sender, endpoint, &ServerMessage::MessageRejected(server_handshake, reason));
+                return;
+            }
+            let (id, token) = game.players.identify(endpoint, token);
+            let mut negotiated = handshake.capabilities & CAPABILITIES;
+            // a player coming back keeps playing or watching as before
+            if let Some(state) = game.players.state(id) {
+                negotiated = (negotiated & !CAPABILITY_SPECTATE) | (state.capabilities & CAPABILITY_SPECTATE);
+            }
+            // spectators fly through walls, there is nothing to simulate
+            if negotiated & CAPABILITY_SPECTATE != 0 {
+                negotiated &= !CAPABILITY_INPUT;
+            }
+            send(&game.packet_sender, endpoint, &ServerMessage::MessageWelcome(Handshake { protocol_version: PROTOCOL_VERSION, capabilities: negotiated }));
+            send(&game.packet_sender, endpoint, &ServerMessage::MessageIdentity(id, token));
+            // it is only broadcast when it changes, which a spectator joining does not do
+            send(&game.packet_sender, endpoint, &ServerMessage::MessageScoreboard(game.scores.clone()));
+            if negotiated & CAPABILITY_VOTE != 0 {
+                send(&game.packet_sender, endpoint, &ServerMessage::MessagePlaylist(game.rotation.names()));
+            }
+            // still playing, only their address changed
+            if let Some(state) = game.players.state_mut(id) {
+                state.last_seen = Instant::now();
+                state.skin = skin;
+                state.capabilities = negotiated;
+                if negotiated & CAPABILITY_INPUT == 0 {
+                    state.input = None;
+                }
+                else if state.input.is_none() {
+                    state.input = Some(InputState::new());
+                }
+                if !game.silent {
+                    println!("{} (player {}) is now at {}", nickname, id, endpoint);
+                }
+                return;
+            }
+            let spectator = negotiated & CAPABILITY_SPECTATE != 0;
+            if !game.silent {
+                println!("{} (player {}) joined from {}{}", nickname, id, endpoint, if spectator { " to spectate" } else { "" });
+            }
+            broadcast(&game.packet_sender, &game.players, &ServerMessage::MessagePlayerJoined(nickname.clone()));
+            // spectators get the level too, but have no position others could see or coins could be picked from
+            let position = send_level(&game.packet_sender, endpoint, &game.level, &game.round.gold_coins);
+            if !spectator {
+                game.round.join(id);
+            }
+            game.players.join(id, PlayerState {
+                nickname: nickname,
+                skin: skin,
+                capabilities: negotiated,
+                position: if spectator { None } else { Some(position) },
+                moved_at: Instant::now(),
+                last_seen: Instant::now(),
+                input: if negotiated & CAPABILITY_INPUT != 0 { Some(InputState::new()) } else { None },
+            });
+            let textures_message = ServerMessage::MessageText(String::from("Hello !"), Duration::from_secs(10));
+            send(&game.packet_sender, endpoint, &textures_message);
+        },
+        // nothing but a hello is accepted before the handshake
+        (_, None) => {},
+        (ClientMessage::MessagePosition(pos), Some(id)) => {
+            let now = Instant::now();
+            let state = match game.players.state_mut(id) {
+                Some(state) => state,
+                None => return,
+            };
+            state.last_seen = now;
+            let elapsed = now - state.moved_at;
+            // in input mode the position is only a heartbeat, the server simulates the real one,
+            // and spectators only send it to get the others positions back
+            if state.input.is_none() && !state.spectating() {
+                match movement::check(state.position.as_ref(), &pos, elapsed, &game.level) {
+                    Ok(()) => {
+                        state.position = Some(pos);
+                        state.moved_at = now;
+                    },
+                    Err(reason) => {
+                        if !game.silent {
+                            println!("player {} moved {}, sending them back", id, reason);
+                        }
+                        if let Some(previous) = &state.position {
+                            let correction = Position { dir_x: pos.dir_x, dir_y: pos.dir_y, speed: 0.0, ..previous.clone() };
+                            send(&game.packet_sender, endpoint, &ServerMessage::MessageTeleport(correction));
+                        }
+                    },
+                }
+            }
 
-fn random_level() -> levels::Level {
-    /*
-    let mut rng = rand::thread_rng();
-    match rng.gen_range(0, 3) {
-        0 => levels::spyral(),
-        1 => levels::rat_race(),
-        2 => levels::trapped(),
-        _ => levels::first(),
+            if game.debug {
+                println!("positions {:?}", game.players.positions());
+            }
+            let mut positions_clone = HashMap::new();
+            for (key, state) in game.players.states() {
+                match &state.position {
+                    Some(position) if *key != id => {
+                        positions_clone.insert(*key, RemotePlayer { nickname: state.nickname.clone(), skin: state.skin, position: position.clone() });
+                    },
+                    _ => {},
+                }
+            }
+            let positions_message = ServerMessage::MessagePositions((now - game.started).as_millis() as u64, positions_clone);
+            send(&game.packet_sender, endpoint, &positions_message);
+        },
+        (ClientMessage::MessageAction(_, _, _), Some(id)) if spectating(&game.players, id) => {},
+        (ClientMessage::MessageAction(_, _, action), Some(id)) => {
+            // triggers fire where we last saw the player, not wherever the packet claims they stand
+            let (pos_x, pos_y) = match game.players.state(id).and_then(|state| state.position.as_ref()) {
+                Some(position) => (position.x, position.y),
+                None => return,
+            };
+            let (old_map, old_layer, old_doors) = (game.level.world_map.clone(), game.level.world_layer.clone(), game.level.doors.clone());
+            let texts = triggers::fire(&game.level.triggers, &mut game.open_doors, pos_x, pos_y, action, &mut game.level.world_map, &mut game.level.world_layer, &mut game.level.doors);
+            for (text, duration) in texts {
+                let text_message = ServerMessage::MessageText(text, duration);
+                send(&game.packet_sender, endpoint, &text_message);
+            }
+            let changes = tile_changes(&old_map, &old_layer, &game.level.world_map, &game.level.world_layer);
+            if !changes.is_empty() {
+                broadcast(&game.packet_sender, &game.players, &ServerMessage::MessageTileChanges(changes));
+            }
+            if game.level.doors != old_doors {
+                broadcast(&game.packet_sender, &game.players, &ServerMessage::MessageDoors(game.level.doors.clone()));
+            }
+        },
+        (ClientMessage::MessageBye, Some(id)) => {
+            remove_player(id, "left", &game.packet_sender, &mut game.players, &mut game.round, &mut game.rotation, game.silent);
+            game.decode_errors.remove(&endpoint);
+        },
+        (ClientMessage::MessageResync, Some(_)) => {
+            send(&game.packet_sender, endpoint, &ServerMessage::MessageWorldMap(game.level.world_map.clone()));
+            send(&game.packet_sender, endpoint, &ServerMessage::MessageWorldLayer(game.level.world_layer.clone()));
+            send(&game.packet_sender, endpoint, &ServerMessage::MessageFloor(game.level.floor.clone()));
+            send(&game.packet_sender, endpoint, &ServerMessage::MessageCeiling(game.level.ceiling.clone()));
+            send(&game.packet_sender, endpoint, &ServerMessage::MessageFog(game.level.fog.clone()));
+            send(&game.packet_sender, endpoint, &ServerMessage::MessageLighting(game.level.lighting.clone()));
+            send(&game.packet_sender, endpoint, &ServerMessage::MessageDoors(game.level.doors.clone()));
+        },
+        (ClientMessage::MessageInput(tick, command), Some(id)) => {
+            let now = Instant::now();
+            let state = match game.players.state_mut(id) {
+                Some(state) => state,
+                None => return,
+            };
+            let input = match state.input.as_mut() {
+                Some(input) => input,
+                None => return,
+            };
+            // replayed or too many inputs are dropped, the next snapshot corrects the client
+            if tick <= input.tick || !input.spend(now) {
+                return;
+            }
+            input.tick = tick;
+            if let Some(position) = &state.position {
+                state.position = Some(simulate(command, position, &game.level));
+                state.moved_at = now;
+            }
+        },
+        (ClientMessage::MessageVote(index), Some(id)) => {
+            if let Some(votes) = game.rotation.vote(id, index as usize) {
+                let text = format!("{}: {} votes", game.rotation.name(index as usize), votes);
+                for endpoint in voters(&game.players) {
+                    send(&game.packet_sender, endpoint, &ServerMessage::MessageText(text.clone(), Duration::from_secs(5)));
+                }
+            }
+        },
+        (ClientMessage::MessageRcon(password, command), Some(id)) => {
+            if game.rcon_password.as_ref().map_or(false, |expected| *expected == password) {
+                if !game.silent {
+                    println!("player {} runs {}", id, command);
+                }
+                game.commands.push((command, Some(endpoint)));
+            }
+            else {
+                // guessing the password costs as much as sending garbage
+                let errors = game.decode_errors.entry(endpoint).or_insert(0);
+                *errors += 1;
+                if *errors >= MAX_DECODE_ERRORS {
+                    send(&game.packet_sender, endpoint, &ServerMessage::MessageKicked(String::from("too many wrong admin passwords")));
+                    remove_player(id, "was kicked", &game.packet_sender, &mut game.players, &mut game.round, &mut game.rotation, game.silent);
+                    game.decode_errors.remove(&endpoint);
+                    game.kicked.insert(endpoint, Instant::now() + KICKED_FOR);
+                }
+                else {
+                    send(&game.packet_sender, endpoint, &ServerMessage::MessageText(String::from("wrong admin password"), Duration::from_secs(5)));
+                }
+            }
+        },
+        (ClientMessage::MessageChat(text), Some(id)) => {
+            let text: String = text.chars().filter(|c| !c.is_control()).take(MAX_CHAT_LENGTH).collect();
+            if text.trim().is_empty() {
+                return;
+            }
+            let nickname = game.players.nickname(id);
+            if !game.silent {
+                println!("{}: {}", nickname, text);
+            }
+            broadcast(&game.packet_sender, &game.players, &ServerMessage::MessageChat(nickname, text));
+        },
+        // turned into a hello above
+        (ClientMessage::MessageReconnect(..), Some(_)) => {},
     }
-    */
-    levels::metro()
 }
 
-pub fn server(address: String, silent: bool) {
-    let mut coins_found = 0;
-    let mut level = random_level();
+/// Runs the game on `socket`. An embedded server shares the terminal with the client: it prints nothing and reads no commands.
+pub fn server(mut socket: Socket, config: ServerConfig, embedded: bool) {
+    let silent = embedded || config.log_level == LogLevel::Quiet;
+    let (rotation, level) = match rotation::Rotation::new(config.levels.clone()) {
+        Ok(res) => res,
+        Err(e) => {
+            println!("could not load level: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let mut gold_coins = vec![
-        random_position(&level.world_map)
-    ];
-    // Creates the socket
-    let mut socket = Socket::bind(address).unwrap();
-    let packet_sender = socket.get_packet_sender();
+    let rules = load_rules(&config.rules, &level.world_map);
+    let mut leaderboard = match Leaderboard::load(LEADERBOARD_FILE) {
+        Ok(leaderboard) => leaderboard,
+        Err(e) => {
+            println!("could not load leaderboard: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut game = Game::new(socket.get_packet_sender(), config, rotation, level, rules, silent);
     let event_receiver = socket.get_event_receiver();
     // Starts the socket, which will start a poll mechanism to receive and send messages.
     let _thread = thread::spawn(move || socket.start_polling());
 
-    let mut positions = HashMap::new();
-    let mut last_seen = HashMap::new();
-    let mut nicknames = HashMap::new();
-    let mut points = HashMap::new();
+    let console = if embedded { None } else { Some(admin::console()) };
+    let mut doors_slid_at = Instant::now();
 
     loop {
-        // Waits until a socket event occurs
-        let result = event_receiver.recv();
+        let stale: Vec<PlayerId> = game.players.states()
+            .filter(|(_, state)| state.last_seen.elapsed() > STALE_AFTER)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in stale {
+            remove_player(key, "timed out", &game.packet_sender, &mut game.players, &mut game.round, &mut game.rotation, game.silent);
+        }
+        let now = Instant::now();
+        game.kicked.retain(|_, until| *until > now);
+        game.players.expire(now);
+        if let Some(console) = &console {
+            while let Ok(line) = console.try_recv() {
+                game.commands.push((line, None));
+            }
+        }
+        for (line, requester) in std::mem::take(&mut game.commands) {
+            let command = admin::parse(&line);
+            let ban = if let Ok(Command::Ban(_)) = command { true } else { false };
+            let output = match command {
+                Err(e) => vec![e],
+                Ok(Command::Players) => {
+                    game.players.states().map(|(id, state)| format!("{} {} {} {}",
+                        id,
+                        state.nickname,
+                        game.players.address(*id).map_or(String::from("?"), |address| address.to_string()),
+                        if state.spectating() { String::from("spectating") } else { format!("{} coins", game.round.points.get(id).cloned().unwrap_or(0)) })).collect()
+                },
+                Ok(Command::Kick(nickname)) | Ok(Command::Ban(nickname)) => {
+                    if ban {
+                        game.banned_nicknames.insert(nickname.clone());
+                    }
+                    match game.players.find(&nickname) {
+                        Some(id) => {
+                            let reason = if ban { "was banned" } else { "was kicked" };
+                            if let Some(endpoint) = game.players.address(id) {
+                                if ban {
+                                    game.banned_addresses.insert(endpoint.ip());
+                                }
+                                send(&game.packet_sender, endpoint, &ServerMessage::MessageKicked(format!("{} by an admin", reason)));
+                            }
+                            remove_player(id, reason, &game.packet_sender, &mut game.players, &mut game.round, &mut game.rotation, game.silent);
+                            vec![format!("{} {}", nickname, reason)]
+                        },
+                        None if ban => vec![format!("{} is not here, but is banned", nickname)],
+                        None => vec![format!("{} is not here", nickname)],
+                    }
+                },
+                Ok(Command::Level(name)) => {
+                    match game.rotation.find(&name).map(|index| game.rotation.switch(index)) {
+                        Some(Ok(next_level)) => {
+                            start_level(next_level, &mut game.level, &mut game.open_doors, &mut game.round, &game.packet_sender, &mut game.players);
+                            announce_playlist(&game.packet_sender, &game.players, &game.rotation);
+                            vec![format!("level {}", name)]
+                        },
+                        Some(Err(e)) => vec![format!("could not load {}: {}", name, e)],
+                        None => vec![format!("no level called {}", name)],
+                    }
+                },
+                Ok(Command::Restart) => {
+                    game.round.restart(&game.level.world_map);
+                    broadcast(&game.packet_sender, &game.players, &ServerMessage::MessageGoldCoins(game.round.gold_coins.clone()));
+                    vec![String::from("round restarted")]
+                },
+                Ok(Command::Say(text)) => {
+                    broadcast(&game.packet_sender, &game.players, &ServerMessage::MessageChat(String::from("admin"), text));
+                    vec![]
+                },
+                Ok(Command::Rule(name, value)) => {
+                    match game.round.rules.set(&name, &value, &game.level.world_map) {
+                        Ok(()) => vec![format!("{} = {}", name, value)],
+                        Err(e) => vec![e],
+                    }
+                },
+            };
+            for text in output {
+                match requester {
+                    Some(endpoint) => send(&game.packet_sender, endpoint, &ServerMessage::MessageText(text, Duration::from_secs(10))),
+                    None => println!("{}", text),
+                }
+            }
+        }
+        simulation::slide_doors(&mut game.level.doors, doors_slid_at.elapsed());
+        doors_slid_at = Instant::now();
+        let occupied = game.players.positions().values().map(|position| (position.x as usize, position.y as usize)).collect();
+        let (closed_doors, closed): (Vec<(usize, usize)>, Vec<(usize, usize)>) = triggers::close_doors(&mut game.open_doors, &mut game.level.world_map, &mut game.level.doors, &occupied)
+            .into_iter().partition(|(x, y)| simulation::door_at(&game.level.doors, *x, *y).is_some());
+        if !closed_doors.is_empty() {
+            broadcast(&game.packet_sender, &game.players, &ServerMessage::MessageDoors(game.level.doors.clone()));
+        }
+        if !closed.is_empty() {
+            let changes = closed.iter().map(|(x, y)| (*x as u16, *y as u16, game.level.world_map[*x][*y], game.level.world_layer[*x][*y])).collect();
+            broadcast(&game.packet_sender, &game.players, &ServerMessage::MessageTileChanges(changes));
+        }
+        let events = game.round.update(Instant::now(), &game.players.positions(), &game.level.world_map);
+        if let Err(e) = leaderboard.record(&events, &game.players.nicknames()) {
+            println!("could not save leaderboard: {}", e);
+        }
+        if announce(events, &game.round, &game.packet_sender, &game.players) {
+            match game.rotation.next_level() {
+                Ok(next_level) => {
+                    if !game.silent {
+                        println!("level {}", game.rotation.name(game.rotation.current()));
+                    }
+                    start_level(next_level, &mut game.level, &mut game.open_doors, &mut game.round, &game.packet_sender, &mut game.players);
+                    announce_playlist(&game.packet_sender, &game.players, &game.rotation);
+                },
+                Err(e) => {
+                    println!("could not load next level, keeping the current one: {}", e);
+                    broadcast(&game.packet_sender, &game.players, &ServerMessage::MessageGoldCoins(game.round.gold_coins.clone()));
+                },
+            }
+        }
+        let current_scores = scoreboard(&game.round, &game.players, &leaderboard);
+        if current_scores != game.scores {
+            broadcast(&game.packet_sender, &game.players, &ServerMessage::MessageScoreboard(current_scores.clone()));
+            game.scores = current_scores;
+        }
+        let now = Instant::now();
+        let mut snapshots = vec![];
+        for (key, state) in game.players.states_mut() {
+            if let Some(input) = state.input.as_mut() {
+                if input.acked != input.tick && now - input.snapshot_at >= SNAPSHOT_INTERVAL {
+                    if let Some(position) = &state.position {
+                        snapshots.push((*key, ServerMessage::MessageSnapshot(input.tick, position.clone())));
+                    }
+                    input.acked = input.tick;
+                    input.snapshot_at = now;
+                }
+            }
+        }
+        for (key, snapshot) in snapshots {
+            if let Some(endpoint) = game.players.address(key) {
+                send(&game.packet_sender, endpoint, &snapshot);
+            }
+        }
+        // Waits until a socket event occurs, or a bit to let doors close
+        let result = event_receiver.recv_timeout(Duration::from_millis(100));
 
         match result {
             Ok(socket_event) => {
                 match  socket_event {
-                    SocketEvent::Packet(packet) => {
-                        let endpoint: SocketAddr = packet.addr();
-                        let received_data: &[u8] = packet.payload();
-                        let message = bincode::deserialize::<ClientMessage>(received_data).unwrap();
-                        match message {
-                            ClientMessage::MessagePosition(pos) => {
-                                last_seen.insert(endpoint, Instant::now());
-                                positions.insert(endpoint, pos);
-
-                                let now = Instant::now();
-                                if !silent { 
-                                    println!("positions {:?}", positions);
-                                }
-                                let mut to_remove = vec![];
-                                for (key, _) in &positions {
-                                    if now - last_seen[key] > Duration::from_secs(20) {
-                                        to_remove.push(key.clone());
-                                    }
-                                }
-                                for key in to_remove {
-                                    positions.remove(&key);
-                                    last_seen.remove(&key);
-                                }
-                                let mut positions_clone = HashMap::new();
-                                for (key, value) in &positions {
-                                    if key != &endpoint {
-                                        positions_clone.insert(key.clone(), value.clone());
-                                    }
-                                }
-                                coins_found = check_gold_coins(coins_found, &level.world_map, &packet_sender, &mut gold_coins, &positions, &nicknames, &mut points);
-                                let positions_message = ServerMessage::MessagePositions(positions_clone);
-                                let pos_ser = bincode::serialize(&positions_message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, pos_ser)).unwrap();
-                            },
-                            ClientMessage::MessageAction(pos_x, pos_y, action) => {
-                                (level.on_action)(pos_x, pos_y, action, &mut level.world_map, &mut level.world_layer);
-                                let map_message = ServerMessage::MessageWorldLayer(level.world_layer.clone());
-                                let message_ser = bincode::serialize(&map_message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)).unwrap();
-                                let map_message = ServerMessage::MessageWorldMap(level.world_map.clone());
-                                let message_ser = bincode::serialize(&map_message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)).unwrap();
-                            }
-                            ClientMessage::MessageHello(nickname) => {
-                                nicknames.insert(endpoint, nickname);
-                                points.insert(endpoint, 0);
-                                let (x, y) = random_position(&level.world_map);
-                                let map_message = ServerMessage::MessageTeleport(Position { x: x, y: y, dir_x: -1.0, dir_y: 0.0, speed: 0.0 });
-                                let message_ser = bincode::serialize(&map_message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)).unwrap();
-                                let map_message = ServerMessage::MessageWorldMap(level.world_map.clone());
-                                let message_ser = bincode::serialize(&map_message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)).unwrap();
-                                let map_message = ServerMessage::MessageWorldLayer(level.world_layer.clone());
-                                let message_ser = bincode::serialize(&map_message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)).unwrap();
-                                let sprites_message = ServerMessage::MessageSprites(level.sprites.clone());
-                                let message_ser = bincode::serialize(&sprites_message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)).unwrap();
-                                let textures_message = ServerMessage::MessageTexturesZip(level.url.clone());
-                                let message_ser = bincode::serialize(&textures_message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)).unwrap();
-                                let textures_message = ServerMessage::MessageGoldCoins(gold_coins.clone());
-                                let message_ser = bincode::serialize(&textures_message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)).unwrap();
-                                let message = ServerMessage::MessagePortals(level.portals.clone(), level.portals_destinations.clone());
-                                let message_ser = bincode::serialize(&message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)).unwrap();
-                                let textures_message = ServerMessage::MessageText(String::from("Hello !"), Duration::from_secs(10));
-                                let message_ser = bincode::serialize(&textures_message).unwrap();
-                                packet_sender.send(Packet::reliable_unordered(endpoint, message_ser)).unwrap();
-                            }
+                    SocketEvent::Packet(packet) => dispatch(&mut game, packet.addr(), packet.payload()),
+                    SocketEvent::Connect(_) => { /* players join with a hello */ },
+                    SocketEvent::Timeout(endpoint) => {
+                        if let Some(id) = game.players.id(&endpoint) {
+                            remove_player(id, "timed out", &game.packet_sender, &mut game.players, &mut game.round, &mut game.rotation, game.silent);
                         }
+                        game.decode_errors.remove(&endpoint);
                     },
-                   SocketEvent::Connect(_) => { /* a client connected */ },
-                    SocketEvent::Timeout(_) => { /* a client timed out */},
                 }
             }
+            Err(RecvTimeoutError::Timeout) => {},
             Err(e) => {
                 println!("Something went wrong when receiving, error: {:?}", e);
             }
@@ -190,3 +705,147 @@ pub fn server(address: String, silent: bool) {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::tests::malformed_client_payloads;
+    use crossbeam_channel::Receiver;
+
+    fn game() -> (Game, Receiver<Packet>) {
+        let (packet_sender, packet_receiver) = crossbeam_channel::unbounded();
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/levels/metro.json");
+        let (rotation, level) = rotation::Rotation::new(vec![String::from(path)]).unwrap();
+        let config = ServerConfig { levels: vec![String::from(path)], ..ServerConfig::default() };
+        (Game::new(packet_sender, config, rotation, level, GameRules::default(), true), packet_receiver)
+    }
+
+    /// A player, not a spectator, whose positions are checked
+    fn hello(game: &mut Game, endpoint: SocketAddr) {
+        let handshake = Handshake { protocol_version: PROTOCOL_VERSION, capabilities: CAPABILITY_VOTE };
+        dispatch(game, endpoint, &bincode::serialize(&ClientMessage::MessageHello(handshake, String::from("player"), 0)).unwrap());
+    }
+
+    #[test]
+    fn malformed_packets_do_not_panic() {
+        let (mut game, _packets) = game();
+        let stranger: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let player: SocketAddr = "127.0.0.1:2001".parse().unwrap();
+        hello(&mut game, player);
+        for payload in malformed_client_payloads() {
+            dispatch(&mut game, stranger, &payload);
+            dispatch(&mut game, player, &payload);
+            // keep sending as a player rather than as a kicked endpoint
+            if game.players.id(&player).is_none() {
+                game.kicked.clear();
+                hello(&mut game, player);
+            }
+        }
+    }
+
+    #[test]
+    fn strangers_sending_garbage_get_no_state() {
+        let (mut game, _packets) = game();
+        let stranger: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let hello = bincode::serialize(&ClientMessage::MessageHello(Handshake { protocol_version: PROTOCOL_VERSION, capabilities: CAPABILITIES }, String::from("player"), 0)).unwrap();
+        for length in 0..hello.len() {
+            dispatch(&mut game, stranger, &hello[..length]);
+        }
+        assert!(game.players.id(&stranger).is_none());
+        assert!(game.decode_errors.is_empty());
+    }
+
+    #[test]
+    fn players_sending_garbage_are_kicked() {
+        let (mut game, packets) = game();
+        let player: SocketAddr = "127.0.0.1:2001".parse().unwrap();
+        hello(&mut game, player);
+        assert!(game.players.id(&player).is_some());
+        for _ in 0..MAX_DECODE_ERRORS {
+            dispatch(&mut game, player, &[0xff; 3]);
+        }
+        assert!(game.players.id(&player).is_none());
+        assert!(game.kicked.contains_key(&player));
+        let kicked = packets.try_iter().any(|packet| match decode::<ServerMessage>(packet.payload()) {
+            Ok(ServerMessage::MessageKicked(_)) => true,
+            _ => false,
+        });
+        assert!(kicked);
+        // and what they send next is ignored
+        hello(&mut game, player);
+        assert!(game.players.id(&player).is_none());
+    }
+
+    #[test]
+    fn actions_fire_where_the_server_saw_the_player() {
+        let (mut game, _packets) = game();
+        let player: SocketAddr = "127.0.0.1:2001".parse().unwrap();
+        hello(&mut game, player);
+        let id = game.players.id(&player).unwrap();
+        let lever = bincode::serialize(&ClientMessage::MessageAction(21.5, 3.5, 1)).unwrap();
+        game.players.state_mut(id).unwrap().position = Some(Position { x: 1.5, y: 1.5, dir_x: -1.0, dir_y: 0.0, speed: 0.0 });
+        dispatch(&mut game, player, &lever);
+        assert!(!game.level.doors[0].opening);
+        game.players.state_mut(id).unwrap().position = Some(Position { x: 21.5, y: 3.5, dir_x: -1.0, dir_y: 0.0, speed: 0.0 });
+        dispatch(&mut game, player, &lever);
+        assert!(game.level.doors[0].opening);
+    }
+
+    #[test]
+    fn newcomers_get_the_current_scoreboard() {
+        let (mut game, packets) = game();
+        let spectator: SocketAddr = "127.0.0.1:2002".parse().unwrap();
+        game.scores = vec![ScoreLine { nickname: String::from("player"), coins: 3, rounds_won: 1 }];
+        let handshake = Handshake { protocol_version: PROTOCOL_VERSION, capabilities: CAPABILITIES };
+        dispatch(&mut game, spectator, &bincode::serialize(&ClientMessage::MessageHello(handshake, String::from("spectator"), 0)).unwrap());
+        let scoreboard = packets.try_iter().find_map(|packet| match decode::<ServerMessage>(packet.payload()) {
+            Ok(ServerMessage::MessageScoreboard(lines)) if packet.addr() == spectator => Some(lines),
+            _ => None,
+        });
+        assert_eq!(scoreboard, Some(game.scores.clone()));
+    }
+
+    #[test]
+    fn prediction_matches_the_server_and_replays_after_a_snapshot() {
+        let (game, _packets) = game();
+        let level = &game.level;
+        let start = Position { x: 3.5, y: 3.5, dir_x: -1.0, dir_y: 0.0, speed: 0.0 };
+        // into the wall, along it to the portal at (1.5, 1.5), through it and around
+        let mut commands = vec![];
+        commands.extend(vec![InputCommand::Move(Movement::WalkForward); 30]);
+        commands.extend(vec![InputCommand::Move(Movement::RotateLeft); 16]);
+        commands.extend(vec![InputCommand::Move(Movement::WalkForward); 15]);
+        commands.push(InputCommand::TakePortal(0));
+        commands.extend(vec![InputCommand::Move(Movement::StrafeLeft); 10]);
+        commands.extend(vec![InputCommand::Move(Movement::RotateRight); 5]);
+        commands.extend(vec![InputCommand::Move(Movement::WalkBackward); 10]);
+
+        // the client predicts on its own camera, the way move_player and the portal check do
+        let mut predicted = Camera::from_position(&start);
+        for command in &commands {
+            match command {
+                InputCommand::Move(movement) => {
+                    simulation::apply(*movement, &level.world_map, &level.doors, &mut predicted);
+                },
+                InputCommand::TakePortal(index) => {
+                    predicted.pos_x = level.portals_destinations[*index as usize][0];
+                    predicted.pos_y = level.portals_destinations[*index as usize][1];
+                },
+            }
+        }
+        // the server simulates each command from the position it stored
+        let mut snapshots = vec![start];
+        for command in &commands {
+            let position = simulate(*command, snapshots.last().unwrap(), level);
+            snapshots.push(position);
+        }
+        let server = snapshots.last().unwrap();
+        assert!(server.x > 15.0, "the portal was not taken: {:?}", server);
+        assert_eq!((predicted.pos_x, predicted.pos_y, predicted.dir_x, predicted.dir_y), (server.x, server.y, server.dir_x, server.dir_y));
+
+        // whatever the client predicted, a snapshot at any tick plus a replay of the later inputs lands on the server position
+        for tick in vec![0, 20, 40, 61, commands.len()] {
+            let corrected = simulation::replay(&snapshots[tick], commands[tick..].iter(), &level.world_map, &level.doors, &level.portals, &level.portals_destinations);
+            assert_eq!((corrected.pos_x, corrected.pos_y, corrected.dir_x, corrected.dir_y), (server.x, server.y, server.dir_x, server.dir_y));
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::io::{self, BufRead};
+use std::thread;
+use crossbeam_channel::{unbounded, Receiver};
+
+pub const HELP: &str = "commands: players, kick <nickname>, ban <nickname>, level <name>, restart, say <text>, rule <name> <value>";
+
+/// What an admin can ask the server, from its console or remotely
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Players,
+    Kick(String),
+    Ban(String),
+    Level(String),
+    Restart,
+    Say(String),
+    Rule(String, String),
+}
+
+/// Splits the first word of `line` from the rest
+fn split(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    }
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let (name, argument) = split(line);
+    let needs_argument = |command: fn(String) -> Command| {
+        if argument.is_empty() {
+            Err(format!("{} needs an argument", name))
+        }
+        else {
+            Ok(command(String::from(argument)))
+        }
+    };
+    match name {
+        "players" => Ok(Command::Players),
+        "kick" => needs_argument(Command::Kick),
+        "ban" => needs_argument(Command::Ban),
+        "level" => needs_argument(Command::Level),
+        "restart" => Ok(Command::Restart),
+        "say" => needs_argument(Command::Say),
+        "rule" => {
+            let (rule, value) = split(argument);
+            if value.is_empty() {
+                Err(String::from("rule needs a name and a value"))
+            }
+            else {
+                Ok(Command::Rule(String::from(rule), String::from(value)))
+            }
+        },
+        _ => Err(String::from(HELP)),
+    }
+}
+
+/// Reads the server's standard input in the background, one command per line
+pub fn console() -> Receiver<String> {
+    let (sender, receiver) = unbounded();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) => if sender.send(line).is_err() { break },
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
+}
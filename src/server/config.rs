@@ -0,0 +1,102 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// Read when it exists and no other config file is given
+pub const DEFAULT_CONFIG_FILE: &str = "server.json";
+
+/// How much the server prints
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// only errors
+    Quiet,
+    /// players coming and going, rounds and levels
+    Info,
+    /// also every position received
+    Debug,
+}
+
+impl LogLevel {
+    fn parse(name: &str) -> Result<LogLevel, String> {
+        match name {
+            "quiet" => Ok(LogLevel::Quiet),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("unknown log level {}, expected quiet, info or debug", name)),
+        }
+    }
+}
+
+/// Dedicated server settings, read from a JSON file where every field is optional,
+/// then overridden by command line flags
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// address the server listens on
+    pub bind: String,
+    /// level rotation
+    pub levels: Vec<String>,
+    /// game rules file, default rules are used when it does not exist
+    pub rules: String,
+    /// players, spectators included, allowed at once, unlimited when missing
+    pub max_players: Option<usize>,
+    /// players allowed at once from the same ip address, 4 when missing, unlimited when null
+    pub max_players_per_address: Option<usize>,
+    pub log_level: LogLevel,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: String::from("0.0.0.0:12345"),
+            levels: vec![String::from("levels/metro.json")],
+            rules: String::from("rules.json"),
+            max_players: None,
+            max_players_per_address: Some(4),
+            log_level: LogLevel::Info,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &str) -> Result<ServerConfig, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        serde_json::from_str(&contents).map_err(|e| format!("cannot parse {}: {}", path, e))
+    }
+
+    /// Reads `--config=<file>`, or `server.json` if there is one, then applies the other server flags in `args`.
+    /// Flags this does not know about are left to the caller.
+    pub fn from_args(args: &[String]) -> Result<ServerConfig, String> {
+        let path = args.iter().filter_map(|arg| arg.strip_prefix("--config=")).last();
+        let mut config = match path {
+            Some(path) => ServerConfig::load(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => ServerConfig::load(DEFAULT_CONFIG_FILE)?,
+            None => ServerConfig::default(),
+        };
+        for arg in args {
+            if let Some(address) = arg.strip_prefix("--bind=") {
+                config.bind = String::from(address);
+            }
+            else if let Some(levels) = arg.strip_prefix("--level=") {
+                config.levels = levels.split(',').map(String::from).collect();
+            }
+            else if let Some(rules) = arg.strip_prefix("--rules=") {
+                config.rules = String::from(rules);
+            }
+            else if let Some(max) = arg.strip_prefix("--max-players=") {
+                config.max_players = Some(max.parse().map_err(|_| format!("--max-players expects a number, not {}", max))?);
+            }
+            else if let Some(max) = arg.strip_prefix("--max-players-per-address=") {
+                config.max_players_per_address = Some(max.parse().map_err(|_| format!("--max-players-per-address expects a number, not {}", max))?);
+            }
+            else if let Some(level) = arg.strip_prefix("--log-level=") {
+                config.log_level = LogLevel::parse(level)?;
+            }
+            else if arg == "--silent" {
+                config.log_level = LogLevel::Quiet;
+            }
+        }
+        Ok(config)
+    }
+}
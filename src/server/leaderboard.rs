@@ -0,0 +1,64 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use crate::protocol::PlayerId;
+use super::round::RoundEvent;
+
+/// What a nickname achieved on this server, ever
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Record {
+    pub coins: u32,
+    pub rounds_won: u32,
+}
+
+/// Records by nickname, since player ids do not survive a restart
+pub struct Leaderboard {
+    path: String,
+    records: HashMap<String, Record>,
+}
+
+impl Leaderboard {
+    /// Reads the leaderboard from `path`, starting an empty one if there is none yet
+    pub fn load(path: &str) -> Result<Leaderboard, String> {
+        let records = if Path::new(path).exists() {
+            let contents = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+            serde_json::from_str(&contents).map_err(|e| format!("cannot parse {}: {}", path, e))?
+        }
+        else {
+            HashMap::new()
+        };
+        Ok(Leaderboard { path: String::from(path), records: records })
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(&self.records).map_err(|e| e.to_string())?;
+        fs::write(&self.path, contents).map_err(|e| format!("cannot write {}: {}", self.path, e))
+    }
+
+    /// Counts picked coins and won rounds, and saves the leaderboard if it changed
+    pub fn record(&mut self, events: &Vec<RoundEvent>, nicknames: &HashMap<PlayerId, String>) -> Result<(), String> {
+        let mut changed = false;
+        for event in events {
+            let (who, coins, rounds_won) = match event {
+                RoundEvent::CoinFound { who, .. } => (*who, 1, 0),
+                RoundEvent::RoundOver { winner: Some(winner) } => (*winner, 0, 1),
+                _ => continue,
+            };
+            if let Some(nickname) = nicknames.get(&who) {
+                let record = self.records.entry(nickname.clone()).or_insert(Record::default());
+                record.coins += coins;
+                record.rounds_won += rounds_won;
+                changed = true;
+            }
+        }
+        if changed {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn rounds_won(&self, nickname: &str) -> u32 {
+        self.records.get(nickname).map_or(0, |record| record.rounds_won)
+    }
+}
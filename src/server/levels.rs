@@ -1,449 +1,444 @@
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use super::triggers::{Trigger, Effect};
+use crate::protocol::{Door, Fog, Lighting};
+
+/// floor cell value of levels without a floor grid, texture 3
+const DEFAULT_FLOOR: u8 = 4;
+/// ceiling cell value of levels without a ceiling grid, texture 6
+const DEFAULT_CEILING: u8 = 7;
+
+/// Levels without fog look the same at any distance
+fn default_fog() -> Fog {
+    Fog { color: [0, 0, 0], density: 0.0 }
+}
+
+/// Levels without lighting look as their textures
+fn default_lighting() -> Lighting {
+    Lighting { ambient: 1.0, lights: vec![] }
+}
+
 pub struct Level {
     pub url: String,
     pub world_map: Vec<Vec<u8>>,
     pub world_layer: Vec<Vec<u8>>,
+    pub doors: Vec<Door>,
+    pub floor: Vec<Vec<u8>>,
+    pub ceiling: Vec<Vec<u8>>,
+    pub sky: Option<String>,
+    pub fog: Fog,
+    pub lighting: Lighting,
     pub sprites: Vec<Vec<f32>>,
     pub portals: Vec<Vec<f32>>,
     pub portals_destinations: Vec<Vec<f32>>,
-    pub on_action: Box<dyn Fn(f32, f32, u8, &mut Vec<Vec<u8>>, &mut Vec<Vec<u8>>) -> ()>,
+    pub triggers: Vec<Trigger>,
 }
 
-pub fn _first() -> Level {
-    Level {
-        url: String::from("https://srv-file10.gofile.io/download/GrF7ZN/wolfenstein_textures.zip"),
-        world_map: vec![
-        vec![8,8,8,8,8,8,8,8,8,8,8,4,4,6,4,4,6,4,6,4,4,4,6,4],
-        vec![8,0,0,0,0,0,0,0,0,0,8,4,0,0,0,0,0,0,0,0,0,0,0,4],
-        vec![8,0,3,3,0,0,0,0,0,8,8,4,0,0,0,0,0,0,0,0,0,0,0,6],
-        vec![8,0,0,3,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,6],
-        vec![8,0,3,3,0,0,0,0,0,8,8,4,0,0,0,0,0,0,0,0,0,0,0,4],
-        vec![8,0,0,0,0,0,0,0,0,0,8,4,0,0,0,0,0,6,6,6,0,6,4,6],
-        vec![8,8,8,8,0,8,8,8,8,8,8,4,4,4,4,4,4,6,0,0,0,0,0,6],
-        vec![7,7,7,7,0,7,7,7,7,0,8,0,8,0,8,0,8,4,0,4,0,6,0,6],
-        vec![7,7,0,0,0,0,0,0,7,8,0,8,0,8,0,8,8,6,0,0,0,0,0,6],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,8,6,0,0,0,0,0,4],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,8,6,0,6,0,6,0,6],
-        vec![7,7,0,0,0,0,0,0,7,8,0,8,0,8,0,8,8,6,4,6,0,6,6,6],
-        vec![7,7,7,7,0,7,7,7,7,8,8,4,0,6,8,4,8,3,3,3,0,3,3,3],
-        vec![2,2,2,2,0,2,2,2,2,4,6,4,0,0,6,0,6,3,0,0,0,0,0,3],
-        vec![2,2,0,0,0,0,0,2,2,4,0,0,0,0,0,0,4,3,0,0,0,0,0,3],
-        vec![2,0,0,0,0,0,0,0,2,4,0,0,0,0,0,0,4,3,0,0,0,0,0,3],
-        vec![1,0,0,0,0,0,0,0,1,4,4,4,4,4,6,0,6,3,3,0,0,0,3,3],
-        vec![2,0,0,0,0,0,0,0,2,2,2,1,2,2,2,6,6,0,0,5,0,5,5,5],
-        vec![2,2,0,0,0,0,0,2,2,2,0,0,0,2,2,5,5,0,5,0,0,0,5,5],
-        vec![2,0,0,0,0,0,0,0,2,0,0,0,0,0,2,5,0,5,0,5,0,5,0,5],
-        vec![1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,5],
-        vec![2,0,0,0,0,0,0,0,2,0,0,0,0,0,2,5,0,5,0,5,0,5,0,5],
-        vec![2,2,0,0,0,0,0,2,2,2,0,0,0,2,2,5,5,5,5,0,0,0,5,5],
-        vec![2,2,2,2,1,2,2,2,2,2,2,1,2,2,2,5,5,5,5,5,5,5,5,5]
-            ],
-            world_layer: vec![
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-                vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
-                    ],
-                    sprites: vec![
-                        //green light in front of playerstart
-                        vec![20.5, 11.5, 10.0],
-                        //green lights in every room
-                        vec![18.5,4.5, 10.0],
-                        vec![10.0,4.5, 10.0],
-                        vec![10.0,12.5,10.0],
-                        vec![3.5, 6.5, 10.0],
-                        vec![3.5, 20.5,10.0],
-                        vec![3.5, 14.5,10.0],
-                        vec![14.5,20.5,10.0],
+/// On-disk representation of a level, see the "level format" section of the README.
+#[derive(Deserialize)]
+struct LevelFile {
+    url: String,
+    texture_count: usize,
+    world_map: Vec<Vec<u8>>,
+    world_layer: Vec<Vec<u8>>,
+    #[serde(default)]
+    doors: Vec<(usize, usize)>,
+    #[serde(default)]
+    floor: Vec<Vec<u8>>,
+    #[serde(default)]
+    ceiling: Vec<Vec<u8>>,
+    #[serde(default)]
+    sky: Option<String>,
+    #[serde(default = "default_fog")]
+    fog: Fog,
+    #[serde(default = "default_lighting")]
+    lighting: Lighting,
+    #[serde(default)]
+    sprites: Vec<Vec<f32>>,
+    #[serde(default)]
+    portals: Vec<Vec<f32>>,
+    #[serde(default)]
+    portals_destinations: Vec<Vec<f32>>,
+    #[serde(default)]
+    triggers: Vec<Trigger>,
+}
 
-                        //row of pillars in front of wall: fisheye test
-                        vec![18.5, 10.5, 9.0],
-                        vec![18.5, 11.5, 9.0],
-                        vec![18.5, 12.5, 9.0],
+#[derive(Debug)]
+pub enum LevelError {
+    Io(String, std::io::Error),
+    Parse(String, serde_json::Error),
+    EmptyMap,
+    NoFreeCell,
+    EmptyPlaylist,
+    RaggedRow { grid: &'static str, row: usize, expected: usize, found: usize },
+    RowCount { grid: &'static str, expected: usize, found: usize },
+    UnknownTexture { grid: &'static str, x: usize, y: usize, id: usize, texture_count: usize },
+    UnknownSpriteTexture { sprite: usize, id: f32, texture_count: usize },
+    MalformedEntry { list: &'static str, index: usize, expected: usize, found: usize },
+    EntryOutOfMap { list: &'static str, index: usize, x: f32, y: f32 },
+    PortalWithoutDestination { portal: usize },
+    DestinationWithoutPortal { destination: usize },
+    TriggerOutOfMap { trigger: usize, x: usize, y: usize },
+    TriggerUnknownTexture { trigger: usize, id: usize, texture_count: usize },
+    TriggerNotADoor { trigger: usize, x: usize, y: usize },
+    TriggerBadSeconds { trigger: usize, seconds: f32 },
+    DoorOutOfMap { door: usize, x: usize, y: usize },
+    DoorWithoutTexture { door: usize, x: usize, y: usize },
+    BadFogDensity(f32),
+    BadAmbientLight(f32),
+    BadLightRadius { light: usize, radius: f32 },
+    LightNotInEmptyCell { light: usize, x: f32, y: f32 },
+}
 
-                        //some barrels around the map
-                        vec![21.5, 1.5, 8.0],
-                        vec![15.5, 1.5, 8.0],
-                        vec![16.0, 1.8, 8.0],
-                        vec![16.2, 1.2, 8.0],
-                        vec![3.5,  2.5, 8.0],
-                        vec![9.5, 15.5, 8.0],
-                        vec![10.0, 15.1,8.0],
-                        vec![10.5, 15.8,8.0],
-                        ],
-                        portals: vec![
-                            vec![20.5, 10.1, 0.0],
-                            vec![10.0, 10.0, 1.0],
-                        ],
-                        portals_destinations: vec![
-                            vec![10.0, 10.0],
-                            vec![20.5, 10.1],
-                        ],
-                        on_action: Box::new(|_x, _y, _action, _world_map, _level| {  }),
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LevelError::Io(path, e) => write!(f, "cannot read {}: {}", path, e),
+            LevelError::Parse(path, e) => write!(f, "cannot parse {}: {}", path, e),
+            LevelError::EmptyMap => write!(f, "world_map is empty"),
+            LevelError::NoFreeCell => write!(f, "world_map has no empty cell to play in"),
+            LevelError::EmptyPlaylist => write!(f, "no level given"),
+            LevelError::RaggedRow { grid, row, expected, found } =>
+                write!(f, "{} row {} has {} cells, expected {}", grid, row, found, expected),
+            LevelError::RowCount { grid, expected, found } =>
+                write!(f, "{} has {} rows, expected {}", grid, found, expected),
+            LevelError::UnknownTexture { grid, x, y, id, texture_count } =>
+                write!(f, "{}[{}][{}] uses texture {} but the texture pack only has {}", grid, x, y, id, texture_count),
+            LevelError::UnknownSpriteTexture { sprite, id, texture_count } =>
+                write!(f, "sprite {} uses texture {} but the texture pack only has {}", sprite, id, texture_count),
+            LevelError::MalformedEntry { list, index, expected, found } =>
+                write!(f, "{} entry {} has {} values, expected {}", list, index, found, expected),
+            LevelError::EntryOutOfMap { list, index, x, y } =>
+                write!(f, "{} entry {} at ({}, {}) is outside of the map", list, index, x, y),
+            LevelError::PortalWithoutDestination { portal } =>
+                write!(f, "portal {} has no destination", portal),
+            LevelError::DestinationWithoutPortal { destination } =>
+                write!(f, "portal destination {} has no portal", destination),
+            LevelError::TriggerOutOfMap { trigger, x, y } =>
+                write!(f, "trigger {} refers to cell [{}][{}] outside of the map", trigger, x, y),
+            LevelError::TriggerUnknownTexture { trigger, id, texture_count } =>
+                write!(f, "trigger {} sets texture {} but the texture pack only has {}", trigger, id, texture_count),
+            LevelError::TriggerNotADoor { trigger, x, y } =>
+                write!(f, "trigger {} toggles cell [{}][{}] which is not a door", trigger, x, y),
+            LevelError::TriggerBadSeconds { trigger, seconds } =>
+                write!(f, "trigger {} opens a door for {} seconds, expected a finite number of 0 or more", trigger, seconds),
+            LevelError::DoorOutOfMap { door, x, y } =>
+                write!(f, "door {} is at cell [{}][{}] outside of the map", door, x, y),
+            LevelError::DoorWithoutTexture { door, x, y } =>
+                write!(f, "door {} at cell [{}][{}] has no texture in world_map", door, x, y),
+            LevelError::BadFogDensity(density) => write!(f, "fog density {} should be 0 or more", density),
+            LevelError::BadAmbientLight(ambient) => write!(f, "ambient light {} should be 0 or more", ambient),
+            LevelError::BadLightRadius { light, radius } =>
+                write!(f, "light {} has radius {}, expected more than 0", light, radius),
+            LevelError::LightNotInEmptyCell { light, x, y } =>
+                write!(f, "light {} at ({}, {}) is not in an empty cell of world_map", light, x, y),
+        }
     }
 }
-pub fn _rat_race() -> Level {
-    Level { url: String::from("https://srv-file10.gofile.io/download/GrF7ZN/wolfenstein_textures.zip"),
-    world_map: vec![
-        vec![4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,0,4,],
-        vec![4,0,4,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,4,0,4,],
-        vec![4,0,4,7,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,7,7,7,7,7,0,7,7,7,7,7,7,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,6,6,6,6,6,0,6,6,6,6,6,6,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,6,0,0,0,0,0,0,0,0,0,0,6,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,6,0,6,6,6,6,6,6,6,6,0,6,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,6,0,6,2,2,1,2,2,2,6,0,6,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,6,0,6,2,0,0,0,0,2,6,0,6,7,0,7,4,0,4,],
-        vec![4,0,0,0,0,7,6,0,0,0,0,0,0,0,0,0,0,6,7,0,0,0,0,4,],
-        vec![4,0,4,7,0,7,6,0,6,2,0,2,2,0,2,6,0,6,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,6,0,6,2,0,0,0,0,2,6,0,6,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,6,0,6,2,2,1,2,2,2,6,0,6,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,6,0,6,6,6,6,6,6,6,6,0,6,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,6,0,0,0,0,0,0,0,0,0,0,6,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,6,6,6,6,6,6,0,6,6,6,6,6,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,7,7,7,7,7,7,7,0,7,7,7,7,7,7,0,7,4,0,4,],
-        vec![4,0,4,7,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,7,4,0,4,],
-        vec![4,0,4,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,4,0,4,],
-        vec![4,0,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,0,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,],
-        ],
-        world_layer: vec![
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
-                ],
-                sprites: vec![
-                    vec![13.75,10.291666666666666,9.0],
-                    vec![10.208333333333332,10.291666666666666,9.0],
-                    vec![1.375,1.4166666666666665,8.0],
-                    vec![22.75,1.5,8.0],
-                    vec![1.4166666666666665,20.625,8.0],
-                    vec![4.416666666666667,19.375,8.0],
-                    vec![1.5416666666666665,11.416666666666666,10.0],
-                    vec![22.458333333333332,11.375,10.0],
-                    vec![12.0,11.625,10.0],
-                    vec![7.541666666666666,11.5,10.0],
-                    vec![16.5,11.458333333333334,10.0],
-                    vec![12.458333333333332,18.125,10.0],
-                    vec![11.375,5.875,10.0],
-                    vec![22.708333333333336,20.166666666666668,8.0],
-                    vec![7.666666666666666,1.0833333333333333,9.0],
-                    vec![17.0,1.1666666666666667,9.0],
-                    vec![7.583333333333333,4.208333333333333,9.0],
-                    vec![16.666666666666664,4.333333333333333,9.0],
-                ],
-                portals: vec![],
-                portals_destinations: vec![],
-                on_action: Box::new(|_x, _y, _action, _world_map, _level| {  }),
+
+fn validate_grid(grid_name: &'static str, grid: &Vec<Vec<u8>>, rows: usize, columns: usize, texture_count: usize) -> Result<(), LevelError> {
+    if grid.len() != rows {
+        return Err(LevelError::RowCount { grid: grid_name, expected: rows, found: grid.len() });
     }
+    for (x, row) in grid.iter().enumerate() {
+        if row.len() != columns {
+            return Err(LevelError::RaggedRow { grid: grid_name, row: x, expected: columns, found: row.len() });
+        }
+        for (y, id) in row.iter().enumerate() {
+            // cells hold texture index + 1, 0 meaning empty
+            if *id as usize > texture_count {
+                return Err(LevelError::UnknownTexture { grid: grid_name, x: x, y: y, id: *id as usize, texture_count: texture_count });
+            }
+        }
+    }
+    Ok(())
 }
-pub fn _spyral() -> Level {
-    Level { url: String::from("https://srv-file10.gofile.io/download/GrF7ZN/wolfenstein_textures.zip"),
-    world_map: vec![
-        vec![2,2,1,2,2,2,2,2,1,2,2,2,2,2,2,1,2,2,2,2,2,2,2,2,],
-        vec![2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,2,],
-        vec![2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,0,1,],
-        vec![2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,2,0,2,],
-        vec![2,0,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,0,2,0,2,],
-        vec![2,0,2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,2,0,2,0,2,],
-        vec![1,0,2,0,4,4,4,4,4,4,4,4,4,4,4,4,4,4,0,2,0,2,0,2,],
-        vec![2,0,2,0,4,0,0,0,0,0,0,0,0,0,0,0,0,4,0,2,0,2,0,1,],
-        vec![2,0,2,0,4,0,4,4,4,4,4,4,4,4,4,4,0,4,0,2,0,2,0,2,],
-        vec![2,0,2,0,4,0,4,0,0,0,0,0,0,0,0,4,0,4,0,2,0,2,0,2,],
-        vec![2,0,2,0,4,0,4,0,5,5,5,5,5,5,0,4,0,4,0,2,0,2,0,2,],
-        vec![1,0,2,0,4,0,4,0,5,0,0,0,0,5,0,4,0,4,0,2,0,2,0,2,],
-        vec![2,0,2,0,4,0,4,0,5,0,0,6,0,5,0,4,0,4,0,2,0,2,0,1,],
-        vec![2,0,2,0,4,0,4,0,5,0,5,5,5,5,0,4,0,4,0,2,0,2,0,2,],
-        vec![2,0,2,0,4,0,4,0,5,0,0,0,0,0,0,4,0,4,0,2,0,2,0,2,],
-        vec![2,0,2,0,4,0,4,0,5,5,5,5,5,5,5,4,0,4,0,2,0,2,0,2,],
-        vec![1,0,2,0,4,0,4,0,0,0,0,0,0,0,0,0,0,4,0,2,0,2,0,2,],
-        vec![2,0,2,0,4,0,4,4,4,4,4,4,4,4,4,4,4,4,0,2,0,2,0,2,],
-        vec![2,0,2,0,4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,2,0,2,0,1,],
-        vec![2,0,2,0,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,2,0,2,0,2,],
-        vec![2,0,2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,2,0,2,],
-        vec![2,0,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,0,2,],
-        vec![2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,2,],
-        vec![2,1,2,2,2,2,2,1,2,2,2,2,2,1,2,2,2,2,2,1,2,2,2,2,],
-        ],
-        world_layer: vec![
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
-                ],
-                sprites: vec![
-                    vec![5.375,1.5416666666666665,10.0],
-                    vec![12.791666666666668,1.5,10.0],
-                    vec![22.458333333333332,6.958333333333334,10.0],
-                    vec![22.458333333333332,16.083333333333332,10.0],
-                    vec![1.4583333333333333,14.166666666666668,10.0],
-                    vec![3.458333333333333,17.208333333333332,10.0],
-                    vec![3.4166666666666665,11.166666666666668,10.0],
-                    vec![5.583333333333334,10.708333333333334,10.0],
-                    vec![7.458333333333334,13.916666666666668,10.0],
-                    vec![16.375,11.875,10.0],
-                    vec![18.625,9.458333333333332,10.0],
-                    vec![18.708333333333332,15.5,10.0],
-                    vec![20.416666666666664,12.0,10.0],
-                    vec![1.4166666666666665,3.3333333333333335,9.0],
-                    vec![20.708333333333332,3.291666666666667,9.0],
-                    vec![9.291666666666668,14.625,9.0],
-                    vec![9.25,11.416666666666666,9.0],
-                    vec![11.541666666666668,11.5,8.0],
-                    vec![12.416666666666668,12.583333333333334,8.0],
-                    vec![16.291666666666664,16.5,8.0],
-                    vec![5.416666666666667,18.583333333333336,8.0],
-                    vec![3.458333333333333,20.5,8.0],
-                    vec![3.666666666666667,5.5,8.0],
-                    vec![10.333333333333334,3.208333333333333,8.0],
-                    vec![20.583333333333332,20.166666666666668,8.0],
-                    vec![22.708333333333336,1.3333333333333333,8.0],
-                    ],
-                    portals: vec![
-                        vec![1.5, 1.5, 0.0],
-                        vec![11.5, 11.5, 1.0],
-                    ],
-                    portals_destinations: vec![
-                        vec![11.5, 11.5],
-                        vec![1.5, 10.1],
-                    ],
-                    on_action: Box::new(|_x, _y, _action, _world_map, _level| {  }),
+
+/// Checks each entry has `expected` values, the first two being a place on a map of `rows` by `columns` cells
+fn validate_entries(list: &'static str, entries: &Vec<Vec<f32>>, expected: usize, rows: usize, columns: usize) -> Result<(), LevelError> {
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.len() != expected {
+            return Err(LevelError::MalformedEntry { list: list, index: i, expected: expected, found: entry.len() });
+        }
+        let (x, y) = (entry[0], entry[1]);
+        // written so that NaN is out too
+        if !(x >= 0.0 && y >= 0.0 && x < rows as f32 && y < columns as f32) {
+            return Err(LevelError::EntryOutOfMap { list: list, index: i, x: x, y: y });
+        }
     }
+    Ok(())
 }
-pub fn _trapped() -> Level {
-    Level { url: String::from("https://srv-file10.gofile.io/download/GrF7ZN/wolfenstein_textures.zip"),
-    world_map: vec![
-        vec![7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,7,7,7,7,7,7,7,7,7,7,7,7,0,7,7,7,7,7,7,7,7,7,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,7,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,7,],
-        vec![7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,],
 
-        ],
-        world_layer: vec![
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
-                ],
-                sprites: vec![
-                    vec![17.958333333333332,17.833333333333336,10.0],
-                    vec![17.875,5.75,10.0],
-                    vec![6.416666666666666,5.791666666666667,10.0],
-                    vec![6.375,18.458333333333332,10.0],
-                    vec![1.3333333333333333,1.3333333333333333,8.0],
-                    vec![11.708333333333332,1.2916666666666667,8.0],
-                    vec![13.458333333333332,2.2083333333333335,8.0],
-                    vec![13.875,1.5833333333333335,8.0],
-                    vec![22.583333333333332,9.5,8.0],
-                    vec![22.166666666666668,17.375,8.0],
-                    vec![13.416666666666668,13.583333333333332,8.0],
-                    vec![11.541666666666668,19.291666666666664,8.0],
-                    vec![11.625,18.833333333333332,8.0],
-                    vec![1.2916666666666667,13.416666666666668,9.0],
-                    vec![1.25,11.541666666666668,9.0],
-                    vec![13.333333333333334,11.5,9.0],
-                    vec![22.625,11.416666666666666,9.0],
-                    vec![22.458333333333332,13.583333333333332,9.0],
-                    vec![13.208333333333332,18.333333333333332,9.0],
+fn validate(level: &LevelFile) -> Result<(), LevelError> {
+    if level.world_map.is_empty() || level.world_map[0].is_empty() {
+        return Err(LevelError::EmptyMap);
+    }
+    let rows = level.world_map.len();
+    let columns = level.world_map[0].len();
+    validate_grid("world_map", &level.world_map, rows, columns, level.texture_count)?;
+    validate_grid("world_layer", &level.world_layer, rows, columns, level.texture_count)?;
+    // players and coins are placed on empty cells
+    if !level.world_map.iter().any(|column| column.contains(&0)) {
+        return Err(LevelError::NoFreeCell);
+    }
+    // both are optional
+    if !level.floor.is_empty() {
+        validate_grid("floor", &level.floor, rows, columns, level.texture_count)?;
+    }
+    if !level.ceiling.is_empty() {
+        validate_grid("ceiling", &level.ceiling, rows, columns, level.texture_count)?;
+    }
+    if !(level.fog.density >= 0.0) {
+        return Err(LevelError::BadFogDensity(level.fog.density));
+    }
+    if !(level.lighting.ambient >= 0.0) {
+        return Err(LevelError::BadAmbientLight(level.lighting.ambient));
+    }
+    if let Some((i, light)) = level.lighting.lights.iter().enumerate().find(|(_, light)| !(light.radius > 0.0)) {
+        return Err(LevelError::BadLightRadius { light: i, radius: light.radius });
+    }
+    // lights are not blocked by walls, so only the cell they sit in tells a misplaced one apart
+    for (i, light) in level.lighting.lights.iter().enumerate() {
+        let inside = light.x >= 0.0 && light.y >= 0.0 && (light.x as usize) < rows && (light.y as usize) < columns;
+        if !inside || level.world_map[light.x as usize][light.y as usize] != 0 {
+            return Err(LevelError::LightNotInEmptyCell { light: i, x: light.x, y: light.y });
+        }
+    }
+    for (i, (x, y)) in level.doors.iter().enumerate() {
+        if *x >= rows || *y >= columns {
+            return Err(LevelError::DoorOutOfMap { door: i, x: *x, y: *y });
+        }
+        // the cell holds the door texture, like a wall
+        if level.world_map[*x][*y] == 0 {
+            return Err(LevelError::DoorWithoutTexture { door: i, x: *x, y: *y });
+        }
+    }
+    validate_entries("sprites", &level.sprites, 3, rows, columns)?;
+    for (i, sprite) in level.sprites.iter().enumerate() {
+        if !sprite[2].is_finite() || sprite[2] < 0.0 || sprite[2] as usize >= level.texture_count {
+            return Err(LevelError::UnknownSpriteTexture { sprite: i, id: sprite[2], texture_count: level.texture_count });
+        }
+    }
+    validate_entries("portals", &level.portals, 3, rows, columns)?;
+    validate_entries("portals_destinations", &level.portals_destinations, 2, rows, columns)?;
+    if level.portals.len() > level.portals_destinations.len() {
+        return Err(LevelError::PortalWithoutDestination { portal: level.portals_destinations.len() });
+    }
+    if level.portals_destinations.len() > level.portals.len() {
+        return Err(LevelError::DestinationWithoutPortal { destination: level.portals.len() });
+    }
+    validate_triggers(level)
+}
+
+fn validate_triggers(level: &LevelFile) -> Result<(), LevelError> {
+    let in_map = |x: usize, y: usize| x < level.world_map.len() && y < level.world_map[x].len();
+    for (i, trigger) in level.triggers.iter().enumerate() {
+        let mut cells = vec![(trigger.x, trigger.y)];
+        let mut values = vec![];
+        let mut doors = vec![];
+        for condition in &trigger.conditions {
+            cells.push((condition.x, condition.y));
+        }
+        for effect in &trigger.effects {
+            match effect {
+                Effect::Set { grid: _, x, y, value } => {
+                    cells.push((*x, *y));
+                    values.push(*value);
+                },
+                Effect::Toggle { grid: _, x, y, values: (a, b) } => {
+                    cells.push((*x, *y));
+                    values.push(*a);
+                    values.push(*b);
+                },
+                Effect::OpenDoor { x, y, seconds } => {
+                    if !(seconds.is_finite() && *seconds >= 0.0) {
+                        return Err(LevelError::TriggerBadSeconds { trigger: i, seconds: *seconds });
+                    }
+                    cells.push((*x, *y));
+                },
+                Effect::ToggleDoor { x, y } => {
+                    cells.push((*x, *y));
+                    doors.push((*x, *y));
+                },
+                Effect::Text { text: _, seconds: _ } => {},
+            }
+        }
+        if let Some((x, y)) = cells.into_iter().find(|(x, y)| !in_map(*x, *y)) {
+            return Err(LevelError::TriggerOutOfMap { trigger: i, x: x, y: y });
+        }
+        if let Some(id) = values.into_iter().find(|id| *id as usize > level.texture_count) {
+            return Err(LevelError::TriggerUnknownTexture { trigger: i, id: id as usize, texture_count: level.texture_count });
+        }
+        if let Some((x, y)) = doors.into_iter().find(|door| !level.doors.contains(door)) {
+            return Err(LevelError::TriggerNotADoor { trigger: i, x: x, y: y });
+        }
+    }
+    Ok(())
+}
 
-                    ],
-                    portals: vec![
-                        vec![1.5, 1.5, 0.0],
-                        vec![20.5, 20.5, 1.0],
-                    ],
-                    portals_destinations: vec![
-                        vec![20.5, 20.1],
-                        vec![1.5, 1.5],
-                    ],
-                    on_action: Box::new(|_x, _y, _action, _world_map, _level| {  }),
+pub fn load(path: &str) -> Result<Level, LevelError> {
+    let contents = fs::read_to_string(path).map_err(|e| LevelError::Io(String::from(path), e))?;
+    let level: LevelFile = serde_json::from_str(&contents).map_err(|e| LevelError::Parse(String::from(path), e))?;
+    validate(&level)?;
+    let world_map = &level.world_map;
+    let filled = |grid: Vec<Vec<u8>>, value: u8| if grid.is_empty() {
+        world_map.iter().map(|row| vec![value; row.len()]).collect()
     }
+    else {
+        grid
+    };
+    let floor = filled(level.floor, DEFAULT_FLOOR);
+    let ceiling = filled(level.ceiling, DEFAULT_CEILING);
+    Ok(Level {
+        url: level.url,
+        world_map: level.world_map,
+        world_layer: level.world_layer,
+        doors: level.doors.iter().map(|(x, y)| Door { x: *x as u16, y: *y as u16, open: 0.0, opening: false }).collect(),
+        floor: floor,
+        ceiling: ceiling,
+        sky: level.sky,
+        fog: level.fog,
+        lighting: level.lighting,
+        sprites: level.sprites,
+        portals: level.portals,
+        portals_destinations: level.portals_destinations,
+        triggers: level.triggers,
+    })
 }
-pub fn metro() -> Level {
-    Level { url: String::from("metro/metro.zip"),
-    world_map: vec![
-        vec![4,4,4,4,4,4,4,4,4,4,4,4,4,4,16,4,4,4,4,4,4,4,4,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,5,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,16,],
-        vec![4,0,0,0,0,0,5,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,13,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,6,6,6,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,9,],
-        vec![4,0,12,1,8,1,2,1,1,2,8,1,3,1,8,3,1,2,1,8,1,3,1,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,10,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,6,6,6,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,0,11,11,0,11,11,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,5,5,0,11,11,0,11,0,0,0,0,0,13,0,0,0,0,16,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,11,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,11,11,12,11,11,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,13,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,0,0,4,4,4,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4,],
-        vec![4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,16,4,4,4,4,4,4,4,4,],
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
-        ],
-        world_layer: vec![
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,17],
-            vec![18,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,19,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,18],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,18,0,17,0,0,0,0,0],
-            vec![17,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![18,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,20,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            vec![0,0,0,0,0,0,0,0,0,17,0,0,0,0,0,0,0,0,0,0,0,0,17,0]
-                ],
-                sprites: vec![
-                    vec![17.125,5.875,13.0],
-                    vec![7.375,8.333333333333332,13.0],
-                    vec![3.125,18.291666666666668,13.0],
-                    vec![16.833333333333332,20.708333333333332,13.0],
-                    vec![17.833333333333332,20.708333333333332,14.0],
-                    vec![4.125,12.291666666666668,14.0],
+    /// 4 by 4 cells, three of them free, (2, 1) having texture 1 for a door
+    fn level(changes: serde_json::Value) -> LevelFile {
+        let mut level = json!({
+            "url": "pack.zip",
+            "texture_count": 2,
+            "world_map": [[1, 1, 1, 1], [1, 0, 0, 1], [1, 2, 0, 1], [1, 1, 1, 1]],
+            "world_layer": [[0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
+        });
+        for (key, value) in changes.as_object().unwrap() {
+            level[key] = value.clone();
+        }
+        serde_json::from_value(level).unwrap()
+    }
 
-                ],
-                portals: vec![
-                    vec![1.5, 1.5, 0.0],
-                    vec![20.5, 20.5, 1.0],
-                ],
-                portals_destinations: vec![
-                    vec![20.5, 20.1],
-                    vec![1.5, 1.5],
-                ],
-                on_action: Box::new(|x, y, _action, world_map, world_layer| { 
-                    if x as usize == 21 && y as usize == 3 {
-                        if world_layer[22][3] == 20 {
-                            world_layer[22][3] = 21;
-                            world_map[22][4] = 0;
-                        } else {
-                            world_layer[22][3] = 20;
-                            world_map[22][4] = 4;
-                        }
-                    }
-                }),
+    fn error(level: LevelFile) -> LevelError {
+        match validate(&level) {
+            Err(e) => e,
+            Ok(()) => panic!("the level should be refused"),
+        }
+    }
+
+    fn trigger(effect: serde_json::Value) -> serde_json::Value {
+        json!([{ "action": 1, "x": 1, "y": 1, "effects": [effect] }])
+    }
+
+    #[test]
+    fn shipped_levels_are_valid() {
+        for entry in fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/levels")).unwrap() {
+            let path = entry.unwrap().path();
+            if let Err(e) = load(path.to_str().unwrap()) {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn a_level_with_everything_right_is_valid() {
+        let level = level(json!({
+            "doors": [[2, 1]],
+            "sprites": [[1.5, 1.5, 1.0]],
+            "portals": [[1.5, 2.5, 0.0]],
+            "portals_destinations": [[2.5, 2.5]],
+            "lighting": { "ambient": 0.5, "lights": [{ "x": 1.5, "y": 1.5, "color": [255, 0, 0], "radius": 2.0 }] },
+            "triggers": trigger(json!({ "type": "toggle_door", "x": 2, "y": 1 })),
+        }));
+        assert!(validate(&level).is_ok());
+    }
+
+    #[test]
+    fn unreadable_files_are_refused() {
+        assert!(matches!(load("no/such/level.json"), Err(LevelError::Io(..))));
+        assert!(matches!(load(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")), Err(LevelError::Parse(..))));
+        assert!(matches!(super::super::rotation::Rotation::new(vec![]), Err(LevelError::EmptyPlaylist)));
+    }
+
+    #[test]
+    fn maps_must_have_room_to_play() {
+        assert!(matches!(error(level(json!({ "world_map": [], "world_layer": [] }))), LevelError::EmptyMap));
+        let walls = json!([[1, 1, 1, 1], [1, 1, 1, 1], [1, 1, 1, 1], [1, 1, 1, 1]]);
+        assert!(matches!(error(level(json!({ "world_map": walls }))), LevelError::NoFreeCell));
+    }
+
+    #[test]
+    fn grids_must_match_the_map() {
+        let short_row = json!([[0, 0, 0, 0], [0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        assert!(matches!(error(level(json!({ "world_layer": short_row }))), LevelError::RaggedRow { grid: "world_layer", row: 1, .. }));
+        let missing_row = json!([[0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        assert!(matches!(error(level(json!({ "floor": missing_row }))), LevelError::RowCount { grid: "floor", .. }));
+        let unknown = json!([[1, 1, 1, 1], [1, 0, 0, 1], [1, 3, 0, 1], [1, 1, 1, 1]]);
+        assert!(matches!(error(level(json!({ "world_map": unknown }))), LevelError::UnknownTexture { grid: "world_map", x: 2, y: 1, .. }));
+    }
+
+    #[test]
+    fn sprites_must_use_a_known_texture() {
+        assert!(matches!(error(level(json!({ "sprites": [[1.5, 1.5, 2.0]] }))), LevelError::UnknownSpriteTexture { sprite: 0, .. }));
+        assert!(matches!(error(level(json!({ "sprites": [[1.5, 1.5, -1.0]] }))), LevelError::UnknownSpriteTexture { sprite: 0, .. }));
+        let mut not_a_number = level(json!({ "sprites": [[1.5, 1.5, 0.0]] }));
+        not_a_number.sprites[0][2] = f32::NAN;
+        assert!(matches!(error(not_a_number), LevelError::UnknownSpriteTexture { sprite: 0, .. }));
+    }
+
+    #[test]
+    fn entries_must_be_whole_and_on_the_map() {
+        assert!(matches!(error(level(json!({ "sprites": [[1.5, 1.5]] }))), LevelError::MalformedEntry { list: "sprites", .. }));
+        assert!(matches!(error(level(json!({ "sprites": [[4.5, 1.5, 0.0]] }))), LevelError::EntryOutOfMap { list: "sprites", .. }));
+        let portal_out = level(json!({ "portals": [[1.5, -0.5, 0.0]], "portals_destinations": [[2.5, 2.5]] }));
+        assert!(matches!(error(portal_out), LevelError::EntryOutOfMap { list: "portals", .. }));
+        let destination_out = level(json!({ "portals": [[1.5, 2.5, 0.0]], "portals_destinations": [[2.5, 20.5]] }));
+        assert!(matches!(error(destination_out), LevelError::EntryOutOfMap { list: "portals_destinations", .. }));
+        let mut not_a_number = level(json!({ "portals": [[1.5, 2.5, 0.0]], "portals_destinations": [[2.5, 2.5]] }));
+        not_a_number.portals_destinations[0][0] = f32::NAN;
+        assert!(matches!(error(not_a_number), LevelError::EntryOutOfMap { list: "portals_destinations", .. }));
+    }
+
+    #[test]
+    fn portals_and_destinations_go_in_pairs() {
+        assert!(matches!(error(level(json!({ "portals": [[1.5, 2.5, 0.0]] }))), LevelError::PortalWithoutDestination { portal: 0 }));
+        assert!(matches!(error(level(json!({ "portals_destinations": [[2.5, 2.5]] }))), LevelError::DestinationWithoutPortal { destination: 0 }));
+    }
+
+    #[test]
+    fn triggers_must_make_sense() {
+        let out_of_map = json!([{ "action": 1, "x": 9, "y": 1, "effects": [] }]);
+        assert!(matches!(error(level(json!({ "triggers": out_of_map }))), LevelError::TriggerOutOfMap { trigger: 0, x: 9, y: 1 }));
+        let unknown = trigger(json!({ "type": "set", "grid": "layer", "x": 1, "y": 2, "value": 3 }));
+        assert!(matches!(error(level(json!({ "triggers": unknown }))), LevelError::TriggerUnknownTexture { trigger: 0, id: 3, .. }));
+        let not_a_door = trigger(json!({ "type": "toggle_door", "x": 2, "y": 1 }));
+        assert!(matches!(error(level(json!({ "triggers": not_a_door }))), LevelError::TriggerNotADoor { trigger: 0, x: 2, y: 1 }));
+        let negative = trigger(json!({ "type": "open_door", "x": 2, "y": 1, "seconds": -1.0 }));
+        assert!(matches!(error(level(json!({ "triggers": negative }))), LevelError::TriggerBadSeconds { trigger: 0, .. }));
+    }
+
+    #[test]
+    fn doors_must_be_walls_on_the_map() {
+        assert!(matches!(error(level(json!({ "doors": [[9, 1]] }))), LevelError::DoorOutOfMap { door: 0, x: 9, y: 1 }));
+        assert!(matches!(error(level(json!({ "doors": [[1, 1]] }))), LevelError::DoorWithoutTexture { door: 0, x: 1, y: 1 }));
+    }
+
+    #[test]
+    fn fog_and_lights_must_be_sensible() {
+        assert!(matches!(error(level(json!({ "fog": { "color": [0, 0, 0], "density": -1.0 } }))), LevelError::BadFogDensity(..)));
+        assert!(matches!(error(level(json!({ "lighting": { "ambient": -1.0 } }))), LevelError::BadAmbientLight(..)));
+        let no_radius = json!({ "ambient": 1.0, "lights": [{ "x": 1.5, "y": 1.5, "color": [255, 0, 0], "radius": 0.0 }] });
+        assert!(matches!(error(level(json!({ "lighting": no_radius }))), LevelError::BadLightRadius { light: 0, .. }));
+        let in_wall = json!({ "ambient": 1.0, "lights": [{ "x": 0.5, "y": 0.5, "color": [255, 0, 0], "radius": 2.0 }] });
+        assert!(matches!(error(level(json!({ "lighting": in_wall }))), LevelError::LightNotInEmptyCell { light: 0, .. }));
+        let off_map = json!({ "ambient": 1.0, "lights": [{ "x": 1.5, "y": 8.5, "color": [255, 0, 0], "radius": 2.0 }] });
+        assert!(matches!(error(level(json!({ "lighting": off_map }))), LevelError::LightNotInEmptyCell { light: 0, .. }));
     }
 }
@@ -0,0 +1,174 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+use crate::protocol::Position;
+use crate::protocol::Door;
+use crate::simulation::{self, PORTAL_REACH};
+use super::levels::Level;
+
+/// Fastest a client walks: 0.1 per frame at 60 frames per second
+const MAX_SPEED: f32 = 6.0;
+/// Extra time granted to absorb packets arriving in bursts
+const LAG_ALLOWANCE: f32 = 0.5;
+
+fn cell(x: f32, y: f32) -> (usize, usize) {
+    (x as usize, y as usize)
+}
+
+/// Doors count as open as soon as they start sliding, clients may see them a bit ahead of us
+fn is_free(world_map: &Vec<Vec<u8>>, doors: &Vec<Door>, (x, y): (usize, usize)) -> bool {
+    if x >= world_map.len() || y >= world_map[x].len() {
+        return false;
+    }
+    world_map[x][y] == 0 || simulation::door_at(doors, x, y).map_or(false, |door| door.opening || door.open > 0.0)
+}
+
+fn distance(x0: f32, y0: f32, x1: f32, y1: f32) -> f32 {
+    ((x1 - x0) * (x1 - x0) + (y1 - y0) * (y1 - y0)).sqrt()
+}
+
+/// Whether `to` can be reached from `from` walking through at most `max_steps` free cells
+fn reachable(world_map: &Vec<Vec<u8>>, doors: &Vec<Door>, from: (usize, usize), to: (usize, usize), max_steps: usize) -> bool {
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    visited.insert(from);
+    queue.push_back((from, 0));
+    while let Some(((x, y), steps)) = queue.pop_front() {
+        if (x, y) == to {
+            return true;
+        }
+        if steps == max_steps {
+            continue;
+        }
+        let neighbours = [(x.wrapping_sub(1), y), (x + 1, y), (x, y.wrapping_sub(1)), (x, y + 1)];
+        for next in neighbours.iter() {
+            if is_free(world_map, doors, *next) && visited.insert(*next) {
+                queue.push_back((*next, steps + 1));
+            }
+        }
+    }
+    false
+}
+
+fn took_portal(level: &Level, previous: &Position, next: &Position, reach: f32) -> bool {
+    level.portals.iter().zip(level.portals_destinations.iter()).any(|(portal, destination)| {
+        distance(previous.x, previous.y, portal[0], portal[1]) < PORTAL_REACH
+            && distance(next.x, next.y, destination[0], destination[1]) < reach
+    })
+}
+
+/// Checks a position reported by a client against the last accepted one,
+/// `elapsed` being the time since that one was accepted
+pub fn check(previous: Option<&Position>, next: &Position, elapsed: Duration, level: &Level) -> Result<(), &'static str> {
+    if !(next.x.is_finite() && next.y.is_finite() && next.x >= 0.0 && next.y >= 0.0) {
+        return Err("out of the map");
+    }
+    let next_cell = cell(next.x, next.y);
+    if next_cell.0 >= level.world_map.len() || next_cell.1 >= level.world_map[next_cell.0].len() {
+        return Err("out of the map");
+    }
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return if is_free(&level.world_map, &level.doors, next_cell) { Ok(()) } else { Err("inside a wall") },
+    };
+    let previous_cell = cell(previous.x, previous.y);
+    // a door may have closed on the player, let them stay where they are
+    if previous_cell == next_cell {
+        return Ok(());
+    }
+    if !is_free(&level.world_map, &level.doors, next_cell) {
+        return Err("inside a wall");
+    }
+    let reach = MAX_SPEED * (elapsed.as_secs_f32() + LAG_ALLOWANCE);
+    if took_portal(level, previous, next, reach) {
+        return Ok(());
+    }
+    if distance(previous.x, previous.y, next.x, next.y) > reach {
+        return Err("too fast");
+    }
+    // walking diagonally crosses two cells per unit of distance
+    let max_steps = (reach * 2.0).ceil() as usize + 1;
+    if !reachable(&level.world_map, &level.doors, previous_cell, next_cell, max_steps) {
+        return Err("through a wall");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Fog, Lighting};
+
+    /// A corridor along y, cut in two by a door at y = 6, with a portal from its start to its end
+    fn level(door_open: bool) -> Level {
+        let mut corridor = vec![0; 12];
+        corridor[0] = 1;
+        corridor[6] = 2;
+        corridor[11] = 1;
+        let world_map = vec![vec![1; 12], corridor, vec![1; 12]];
+        Level {
+            url: String::new(),
+            world_layer: vec![vec![0; 12]; 3],
+            doors: vec![Door { x: 1, y: 6, open: if door_open { 1.0 } else { 0.0 }, opening: door_open }],
+            floor: vec![vec![0; 12]; 3],
+            ceiling: vec![vec![0; 12]; 3],
+            sky: None,
+            fog: Fog { color: [0, 0, 0], density: 0.0 },
+            lighting: Lighting { ambient: 1.0, lights: vec![] },
+            sprites: vec![],
+            portals: vec![vec![1.5, 1.5, 0.0]],
+            portals_destinations: vec![vec![1.5, 9.5]],
+            triggers: vec![],
+            world_map: world_map,
+        }
+    }
+
+    fn at(x: f32, y: f32) -> Position {
+        Position { x: x, y: y, dir_x: 0.0, dir_y: 1.0, speed: 0.0 }
+    }
+
+    fn secs(seconds: f32) -> Duration {
+        Duration::from_secs_f32(seconds)
+    }
+
+    #[test]
+    fn first_positions_must_be_in_a_free_cell() {
+        assert_eq!(check(None, &at(1.5, 2.5), secs(0.0), &level(false)), Ok(()));
+        assert_eq!(check(None, &at(0.5, 2.5), secs(0.0), &level(false)), Err("inside a wall"));
+        assert_eq!(check(None, &at(1.5, 6.5), secs(0.0), &level(false)), Err("inside a wall"));
+        assert_eq!(check(None, &at(1.5, 20.5), secs(0.0), &level(false)), Err("out of the map"));
+        assert_eq!(check(None, &at(f32::NAN, 2.5), secs(0.0), &level(false)), Err("out of the map"));
+    }
+
+    #[test]
+    fn moves_into_a_wall_are_refused() {
+        assert_eq!(check(Some(&at(1.5, 2.5)), &at(0.5, 2.5), secs(0.1), &level(false)), Err("inside a wall"));
+        assert_eq!(check(Some(&at(1.5, 10.5)), &at(1.5, 11.5), secs(0.1), &level(false)), Err("inside a wall"));
+    }
+
+    #[test]
+    fn moves_faster_than_walking_are_refused() {
+        // 4 cells with no time elapsed is more than the lag allowance covers
+        assert!(MAX_SPEED * LAG_ALLOWANCE < 4.0);
+        assert_eq!(check(Some(&at(1.5, 1.5)), &at(1.5, 5.5), secs(0.0), &level(false)), Err("too fast"));
+        assert_eq!(check(Some(&at(1.5, 1.5)), &at(1.5, 5.5), secs(1.0), &level(false)), Ok(()));
+    }
+
+    #[test]
+    fn closed_doors_block_the_way_and_open_ones_do_not() {
+        assert_eq!(check(Some(&at(1.5, 5.5)), &at(1.5, 7.5), secs(0.1), &level(false)), Err("through a wall"));
+        assert_eq!(check(Some(&at(1.5, 5.5)), &at(1.5, 7.5), secs(0.1), &level(true)), Ok(()));
+        assert_eq!(check(Some(&at(1.5, 5.5)), &at(1.5, 6.5), secs(0.1), &level(true)), Ok(()));
+    }
+
+    #[test]
+    fn a_door_closing_on_a_player_lets_them_stay() {
+        assert_eq!(check(Some(&at(1.5, 6.2)), &at(1.5, 6.4), secs(0.1), &level(false)), Ok(()));
+    }
+
+    #[test]
+    fn portals_jump_further_than_walking() {
+        assert_eq!(check(Some(&at(1.5, 1.5)), &at(1.5, 9.5), secs(0.0), &level(false)), Ok(()));
+        // far from the portal the same jump is not allowed
+        assert_eq!(check(Some(&at(1.5, 4.5)), &at(1.5, 9.5), secs(0.0), &level(false)), Err("too fast"));
+    }
+}
@@ -0,0 +1,244 @@
+extern crate rand;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use crate::protocol::{PlayerId, Position, CAPABILITY_SPECTATE};
+
+/// inputs per second an input mode client may send, a bit above its frame rate
+const INPUT_RATE: f32 = 70.0;
+/// inputs that may arrive at once after a lag spike
+const INPUT_BURST: f32 = 35.0;
+/// how long the id and token of a player who left stay valid
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Input mode state of a client
+pub struct InputState {
+    /// last input applied
+    pub tick: u32,
+    /// last input acknowledged by a snapshot
+    pub acked: u32,
+    pub snapshot_at: Instant,
+    /// inputs the client may still send right now
+    budget: f32,
+    refilled_at: Instant,
+}
+
+impl InputState {
+    pub fn new() -> InputState {
+        InputState { tick: 0, acked: 0, snapshot_at: Instant::now(), budget: INPUT_BURST, refilled_at: Instant::now() }
+    }
+
+    /// Whether one more input fits in the rate limit
+    pub fn spend(&mut self, now: Instant) -> bool {
+        self.budget = (self.budget + (now - self.refilled_at).as_secs_f32() * INPUT_RATE).min(INPUT_BURST);
+        self.refilled_at = now;
+        if self.budget < 1.0 {
+            return false;
+        }
+        self.budget -= 1.0;
+        true
+    }
+}
+
+/// What the server knows about a player in the game
+pub struct PlayerState {
+    pub nickname: String,
+    pub skin: u8,
+    /// capabilities both sides support
+    pub capabilities: u32,
+    /// spectators have none
+    pub position: Option<Position>,
+    /// when the position was last accepted
+    pub moved_at: Instant,
+    pub last_seen: Instant,
+    /// only for clients whose movement is simulated from their inputs
+    pub input: Option<InputState>,
+}
+
+impl PlayerState {
+    /// Whether the player joined to watch the others
+    pub fn spectating(&self) -> bool {
+        self.capabilities & CAPABILITY_SPECTATE != 0
+    }
+}
+
+/// Who is behind each address, and the state of those in the game. Ids and their tokens are kept
+/// for a while after players leave so that they can come back as themselves, even from another address.
+pub struct Players {
+    next_id: PlayerId,
+    tokens: HashMap<u64, PlayerId>,
+    issued: HashMap<PlayerId, u64>,
+    addresses: HashMap<PlayerId, SocketAddr>,
+    ids: HashMap<SocketAddr, PlayerId>,
+    states: HashMap<PlayerId, PlayerState>,
+    /// when each id was last identified or left the game
+    seen: HashMap<PlayerId, Instant>,
+}
+
+impl Players {
+    pub fn new() -> Players {
+        Players { next_id: 1, tokens: HashMap::new(), issued: HashMap::new(), addresses: HashMap::new(), ids: HashMap::new(), states: HashMap::new(), seen: HashMap::new() }
+    }
+
+    /// Binds `endpoint` to the player `token` was issued to, or to the player already there,
+    /// or to a brand new player. Returns the id and its token.
+    pub fn identify(&mut self, endpoint: SocketAddr, token: Option<u64>) -> (PlayerId, u64) {
+        let id = match self.lookup(&endpoint, token) {
+            Some(id) => id,
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                let token = rand::random();
+                self.tokens.insert(token, id);
+                self.issued.insert(id, token);
+                id
+            },
+        };
+        self.disconnect(id);
+        if let Some(previous) = self.ids.remove(&endpoint) {
+            self.addresses.remove(&previous);
+        }
+        self.addresses.insert(id, endpoint);
+        self.ids.insert(endpoint, id);
+        self.seen.insert(id, Instant::now());
+        (id, self.issued[&id])
+    }
+
+    /// The player `identify` would bind `endpoint` to, if it is not a new one
+    pub fn lookup(&self, endpoint: &SocketAddr, token: Option<u64>) -> Option<PlayerId> {
+        token.and_then(|token| self.tokens.get(&token).cloned())
+            .or(self.ids.get(endpoint).cloned())
+    }
+
+    pub fn id(&self, endpoint: &SocketAddr) -> Option<PlayerId> {
+        self.ids.get(endpoint).cloned()
+    }
+
+    pub fn address(&self, id: PlayerId) -> Option<SocketAddr> {
+        self.addresses.get(&id).cloned()
+    }
+
+    /// Addresses of the players currently connected
+    pub fn addresses(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.addresses.values()
+    }
+
+    /// Forgets the address of a player, who keeps their id and token
+    pub fn disconnect(&mut self, id: PlayerId) {
+        if let Some(endpoint) = self.addresses.remove(&id) {
+            self.ids.remove(&endpoint);
+        }
+    }
+
+    /// Puts player `id` in the game
+    pub fn join(&mut self, id: PlayerId, state: PlayerState) {
+        self.states.insert(id, state);
+    }
+
+    /// Takes player `id` out of the game and forgets their address, returns what we knew about them
+    pub fn leave(&mut self, id: PlayerId) -> Option<PlayerState> {
+        self.disconnect(id);
+        self.seen.insert(id, Instant::now());
+        self.states.remove(&id)
+    }
+
+    /// Forgets the ids and tokens of players out of the game for longer than `FORGET_AFTER`
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<PlayerId> = self.seen.iter()
+            .filter(|(id, seen)| !self.states.contains_key(id) && now - **seen > FORGET_AFTER)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.disconnect(id);
+            self.seen.remove(&id);
+            if let Some(token) = self.issued.remove(&id) {
+                self.tokens.remove(&token);
+            }
+        }
+    }
+
+    pub fn state(&self, id: PlayerId) -> Option<&PlayerState> {
+        self.states.get(&id)
+    }
+
+    pub fn state_mut(&mut self, id: PlayerId) -> Option<&mut PlayerState> {
+        self.states.get_mut(&id)
+    }
+
+    /// Players in the game, spectators included
+    pub fn states(&self) -> impl Iterator<Item = (&PlayerId, &PlayerState)> {
+        self.states.iter()
+    }
+
+    pub fn states_mut(&mut self) -> impl Iterator<Item = (&PlayerId, &mut PlayerState)> {
+        self.states.iter_mut()
+    }
+
+    /// Players in the game, spectators included
+    pub fn count(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Player in the game called `nickname`
+    pub fn find(&self, nickname: &str) -> Option<PlayerId> {
+        self.states.iter().find(|(_, state)| state.nickname == nickname).map(|(id, _)| *id)
+    }
+
+    pub fn nickname(&self, id: PlayerId) -> String {
+        self.states.get(&id).map_or(String::from("?"), |state| state.nickname.clone())
+    }
+
+    pub fn nicknames(&self) -> HashMap<PlayerId, String> {
+        self.states.iter().map(|(id, state)| (*id, state.nickname.clone())).collect()
+    }
+
+    /// Positions of the players who are not spectating
+    pub fn positions(&self) -> HashMap<PlayerId, Position> {
+        self.states.iter().filter_map(|(id, state)| state.position.clone().map(|position| (*id, position))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> PlayerState {
+        PlayerState { nickname: String::from("player"), skin: 0, capabilities: 0, position: None, moved_at: Instant::now(), last_seen: Instant::now(), input: None }
+    }
+
+    #[test]
+    fn tokens_bring_players_back_until_they_expire() {
+        let mut players = Players::new();
+        let endpoint: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let (id, token) = players.identify(endpoint, None);
+        players.join(id, state());
+        players.leave(id);
+        players.expire(Instant::now());
+        assert_eq!(players.lookup(&"127.0.0.1:2001".parse().unwrap(), Some(token)), Some(id));
+        players.expire(Instant::now() + FORGET_AFTER + Duration::from_secs(1));
+        assert_eq!(players.lookup(&"127.0.0.1:2001".parse().unwrap(), Some(token)), None);
+        assert!(players.tokens.is_empty() && players.issued.is_empty() && players.seen.is_empty());
+    }
+
+    #[test]
+    fn players_in_the_game_never_expire() {
+        let mut players = Players::new();
+        let endpoint: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let (id, token) = players.identify(endpoint, None);
+        players.join(id, state());
+        players.expire(Instant::now() + FORGET_AFTER + Duration::from_secs(1));
+        assert_eq!(players.lookup(&endpoint, Some(token)), Some(id));
+        assert_eq!(players.address(id), Some(endpoint));
+    }
+
+    #[test]
+    fn addresses_that_never_joined_expire_too() {
+        let mut players = Players::new();
+        let endpoint: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        players.identify(endpoint, None);
+        players.expire(Instant::now() + FORGET_AFTER + Duration::from_secs(1));
+        assert_eq!(players.id(&endpoint), None);
+        assert!(players.tokens.is_empty());
+    }
+}
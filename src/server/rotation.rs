@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::path::Path;
+use super::levels::{self, Level, LevelError};
+use crate::protocol::PlayerId;
+
+/// Playlist of level files, played in order unless players vote for another one
+pub struct Rotation {
+    paths: Vec<String>,
+    current: usize,
+    votes: HashMap<PlayerId, usize>,
+}
+
+impl Rotation {
+    /// Checks every level of the playlist and returns the first one
+    pub fn new(paths: Vec<String>) -> Result<(Rotation, Level), LevelError> {
+        let mut first = None;
+        for path in &paths {
+            let level = levels::load(path)?;
+            if first.is_none() {
+                first = Some(level);
+            }
+        }
+        let level = first.ok_or(LevelError::EmptyPlaylist)?;
+        Ok((Rotation { paths: paths, current: 0, votes: HashMap::new() }, level))
+    }
+
+    pub fn name(&self, index: usize) -> String {
+        Path::new(&self.paths[index]).file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or(self.paths[index].clone())
+    }
+
+    /// Level names, in playlist order
+    pub fn names(&self) -> Vec<String> {
+        (0..self.paths.len()).map(|index| self.name(index)).collect()
+    }
+
+    /// Registers a vote, returns the number of votes the level now has
+    pub fn vote(&mut self, id: PlayerId, index: usize) -> Option<usize> {
+        if index >= self.paths.len() {
+            return None;
+        }
+        self.votes.insert(id, index);
+        Some(self.votes.values().filter(|v| **v == index).count())
+    }
+
+    pub fn forget(&mut self, id: PlayerId) {
+        self.votes.remove(&id);
+    }
+
+    fn next_index(&self) -> usize {
+        let mut counts = vec![0; self.paths.len()];
+        for index in self.votes.values() {
+            counts[*index] += 1;
+        }
+        let default = (self.current + 1) % self.paths.len();
+        let mut best = default;
+        for (index, count) in counts.iter().enumerate() {
+            if *count > counts[best] {
+                best = index;
+            }
+        }
+        best
+    }
+
+    /// Loads the most voted level, or the next one in the playlist
+    pub fn next_level(&mut self) -> Result<Level, LevelError> {
+        let index = self.next_index();
+        self.switch(index)
+    }
+
+    /// Loads level `index` of the playlist, which must exist
+    pub fn switch(&mut self, index: usize) -> Result<Level, LevelError> {
+        self.votes.clear();
+        let level = levels::load(&self.paths[index])?;
+        self.current = index;
+        Ok(level)
+    }
+
+    /// Index of the level called `name` in the playlist
+    pub fn find(&self, name: &str) -> Option<usize> {
+        (0..self.paths.len()).find(|index| self.name(*index) == name)
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+}
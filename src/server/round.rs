@@ -0,0 +1,410 @@
+extern crate rand;
+
+use serde::{Serialize, Deserialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, Instant};
+use rand::prelude::*;
+use crate::protocol::{PlayerId, Position};
+
+/// Gold coin mode configuration, read from a JSON file where every field is optional
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GameRules {
+    /// coins to find before the round ends
+    pub coins_per_round: u32,
+    /// coins present on the map at the same time
+    pub simultaneous_coins: usize,
+    /// round length in seconds, unlimited if absent
+    pub time_limit: Option<u64>,
+    /// coins a single player needs to win the round right away, if present
+    pub score_limit: Option<u32>,
+    /// seconds before a picked coin appears somewhere else
+    pub respawn_delay: u64,
+    /// how long coin and winner announcements stay on screen, in seconds
+    pub announcement_duration: u64,
+}
+
+impl Default for GameRules {
+    fn default() -> GameRules {
+        GameRules {
+            coins_per_round: 3,
+            simultaneous_coins: 1,
+            time_limit: None,
+            score_limit: None,
+            respawn_delay: 0,
+            announcement_duration: 10,
+        }
+    }
+}
+
+impl GameRules {
+    /// Reads the rules in `path`, which must make a playable round on `world_map`
+    pub fn load(path: &str, world_map: &Vec<Vec<u8>>) -> Result<GameRules, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let rules: GameRules = serde_json::from_str(&contents).map_err(|e| format!("cannot parse {}: {}", path, e))?;
+        rules.validate(world_map).map_err(|e| format!("bad rules in {}: {}", path, e))?;
+        Ok(rules)
+    }
+
+    /// Checks a round can be played on `world_map`, and does not end as soon as it starts
+    pub fn validate(&self, world_map: &Vec<Vec<u8>>) -> Result<(), String> {
+        if self.coins_per_round == 0 {
+            return Err(String::from("coins_per_round should be 1 or more"));
+        }
+        if self.simultaneous_coins == 0 {
+            return Err(String::from("simultaneous_coins should be 1 or more"));
+        }
+        let free = free_cells(world_map).len();
+        if self.simultaneous_coins > free {
+            return Err(format!("simultaneous_coins is {} but the level only has {} empty cells", self.simultaneous_coins, free));
+        }
+        Ok(())
+    }
+
+    /// Changes the rule called `name`, `value` being written as in the rules file,
+    /// unless the rules would then not be valid on `world_map`
+    pub fn set(&mut self, name: &str, value: &str, world_map: &Vec<Vec<u8>>) -> Result<(), String> {
+        let mut rules = serde_json::to_value(&*self).map_err(|e| e.to_string())?;
+        match rules.get_mut(name) {
+            Some(rule) => *rule = serde_json::from_str(value).map_err(|e| format!("bad value {}: {}", value, e))?,
+            None => return Err(format!("no rule called {}", name)),
+        }
+        let rules: GameRules = serde_json::from_value(rules).map_err(|e| format!("bad value {}: {}", value, e))?;
+        rules.validate(world_map)?;
+        *self = rules;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RoundEvent {
+    /// `who` picked a coin, `coins_found` coins were found this round
+    CoinFound { who: PlayerId, coins_found: u32 },
+    /// a coin respawned after its delay
+    CoinRespawned,
+    /// the round ended, `winner` being absent if nobody scored
+    RoundOver { winner: Option<PlayerId> },
+}
+
+/// Cells without a wall
+pub fn free_cells(world_map: &Vec<Vec<u8>>) -> Vec<(usize, usize)> {
+    let mut cells = vec![];
+    for (x, column) in world_map.iter().enumerate() {
+        for (y, cell) in column.iter().enumerate() {
+            if *cell == 0 {
+                cells.push((x, y));
+            }
+        }
+    }
+    cells
+}
+
+/// Center of a random free cell, the corner of the map if there is none
+pub fn random_position(world_map: &Vec<Vec<u8>>) -> (f32, f32) {
+    let mut rng = rand::thread_rng();
+    match free_cells(world_map).choose(&mut rng) {
+        Some((x, y)) => (*x as f32 + 0.5, *y as f32 + 0.5),
+        None => (0.5, 0.5),
+    }
+}
+
+pub struct Round {
+    pub rules: GameRules,
+    pub gold_coins: Vec<(f32, f32)>,
+    pub points: HashMap<PlayerId, u32>,
+    /// points of the players who left during this round, given back if they return
+    absent: HashMap<PlayerId, u32>,
+    /// how many coins had been found when each player last scored, to break ties
+    scored_at: HashMap<PlayerId, u32>,
+    coins_found: u32,
+    started: Instant,
+    respawns: Vec<Instant>,
+}
+
+impl Round {
+    pub fn new(rules: GameRules, world_map: &Vec<Vec<u8>>) -> Round {
+        let mut round = Round {
+            rules: rules,
+            gold_coins: vec![],
+            points: HashMap::new(),
+            absent: HashMap::new(),
+            scored_at: HashMap::new(),
+            coins_found: 0,
+            started: Instant::now(),
+            respawns: vec![],
+        };
+        round.restart(world_map);
+        round
+    }
+
+    /// Starts a new round, keeping the players but resetting their points
+    pub fn restart(&mut self, world_map: &Vec<Vec<u8>>) {
+        self.coins_found = 0;
+        self.started = Instant::now();
+        self.respawns = vec![];
+        self.absent.clear();
+        self.scored_at.clear();
+        // rules checked on another level may ask for more coins than this one has room for
+        let coins = self.rules.simultaneous_coins.min(free_cells(world_map).len());
+        self.gold_coins = (0..coins).map(|_| random_position(world_map)).collect();
+        for (_, points) in self.points.iter_mut() {
+            *points = 0;
+        }
+    }
+
+    pub fn join(&mut self, id: PlayerId) {
+        let points = self.absent.remove(&id).unwrap_or(0);
+        self.points.insert(id, points);
+    }
+
+    pub fn leave(&mut self, id: PlayerId) {
+        if let Some(points) = self.points.remove(&id) {
+            self.absent.insert(id, points);
+        }
+    }
+
+    /// Who has the most points, on a tie whoever got there first, then the lowest id
+    fn winner(&self) -> Option<PlayerId> {
+        self.points.iter()
+            .filter(|(_, points)| **points > 0)
+            .max_by_key(|(id, points)| (**points, Reverse(self.scored_at.get(id).copied().unwrap_or(0)), Reverse(**id)))
+            .map(|(id, _)| *id)
+    }
+
+    fn over(&self, now: Instant) -> bool {
+        let time_up = self.rules.time_limit.map_or(false, |limit| now - self.started >= Duration::from_secs(limit));
+        let score_reached = self.rules.score_limit.map_or(false, |limit| self.points.values().any(|points| *points >= limit));
+        time_up || score_reached || self.coins_found >= self.rules.coins_per_round
+    }
+
+    /// Picks the coins players stand on, respawns coins and ends the round when the rules say so.
+    /// After a `RoundOver` event the round is already restarted.
+    pub fn update(&mut self, now: Instant, positions: &HashMap<PlayerId, Position>, world_map: &Vec<Vec<u8>>) -> Vec<RoundEvent> {
+        let mut events = vec![];
+        let respawn_delay = Duration::from_secs(self.rules.respawn_delay);
+        let due = self.respawns.iter().filter(|picked| now - **picked >= respawn_delay).count();
+        if due > 0 {
+            self.respawns.retain(|picked| now - *picked < respawn_delay);
+            for _ in 0..due {
+                self.gold_coins.push(random_position(world_map));
+            }
+            events.push(RoundEvent::CoinRespawned);
+        }
+        for (id, position) in positions {
+            if !self.points.contains_key(id) {
+                continue;
+            }
+            let cell = (position.x as i32, position.y as i32);
+            let picked = self.gold_coins.iter().position(|coin| (coin.0 as i32, coin.1 as i32) == cell);
+            if let Some(i) = picked {
+                self.gold_coins.remove(i);
+                if self.rules.respawn_delay == 0 {
+                    self.gold_coins.push(random_position(world_map));
+                }
+                else {
+                    self.respawns.push(now);
+                }
+                self.coins_found += 1;
+                self.points.entry(*id).and_modify(|points| *points += 1);
+                self.scored_at.insert(*id, self.coins_found);
+                events.push(RoundEvent::CoinFound { who: *id, coins_found: self.coins_found });
+            }
+        }
+        if self.over(now) {
+            events.push(RoundEvent::RoundOver { winner: self.winner() });
+            self.restart(world_map);
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 3 by 3 cells, only the middle one is free, so that coins always land there
+    fn world_map() -> Vec<Vec<u8>> {
+        vec![vec![1, 1, 1], vec![1, 0, 1], vec![1, 1, 1]]
+    }
+
+    fn position(x: f32, y: f32) -> Position {
+        Position { x: x, y: y, dir_x: -1.0, dir_y: 0.0, speed: 0.0 }
+    }
+
+    fn rules(coins_per_round: u32, respawn_delay: u64) -> GameRules {
+        GameRules { coins_per_round: coins_per_round, respawn_delay: respawn_delay, ..GameRules::default() }
+    }
+
+    fn on_coin(id: PlayerId) -> HashMap<PlayerId, Position> {
+        let mut positions = HashMap::new();
+        positions.insert(id, position(1.5, 1.5));
+        positions
+    }
+
+    #[test]
+    fn coins_are_placed_on_free_cells() {
+        let round = Round::new(rules(3, 0), &world_map());
+        assert_eq!(round.gold_coins, vec![(1.5, 1.5)]);
+        assert_eq!(random_position(&world_map()), (1.5, 1.5));
+    }
+
+    #[test]
+    fn update_picks_the_coin_a_player_stands_on() {
+        let mut round = Round::new(rules(3, 0), &world_map());
+        round.join(1);
+        round.join(2);
+        let mut positions = on_coin(1);
+        positions.insert(2, position(0.5, 0.5));
+        let events = round.update(Instant::now(), &positions, &world_map());
+        assert_eq!(events, vec![RoundEvent::CoinFound { who: 1, coins_found: 1 }]);
+        assert_eq!(round.points[&1], 1);
+        assert_eq!(round.points[&2], 0);
+    }
+
+    #[test]
+    fn players_who_did_not_join_pick_nothing() {
+        let mut round = Round::new(rules(3, 0), &world_map());
+        let events = round.update(Instant::now(), &on_coin(1), &world_map());
+        assert!(events.is_empty());
+        assert_eq!(round.gold_coins.len(), 1);
+    }
+
+    #[test]
+    fn picked_coins_respawn_right_away_without_delay() {
+        let mut round = Round::new(rules(3, 0), &world_map());
+        round.join(1);
+        round.update(Instant::now(), &on_coin(1), &world_map());
+        assert_eq!(round.gold_coins, vec![(1.5, 1.5)]);
+    }
+
+    #[test]
+    fn picked_coins_respawn_after_the_delay() {
+        let mut round = Round::new(rules(3, 5), &world_map());
+        round.join(1);
+        let now = Instant::now();
+        round.update(now, &on_coin(1), &world_map());
+        assert!(round.gold_coins.is_empty());
+        assert!(round.update(now + Duration::from_secs(4), &HashMap::new(), &world_map()).is_empty());
+        let events = round.update(now + Duration::from_secs(5), &HashMap::new(), &world_map());
+        assert_eq!(events, vec![RoundEvent::CoinRespawned]);
+        assert_eq!(round.gold_coins, vec![(1.5, 1.5)]);
+    }
+
+    #[test]
+    fn over_once_enough_coins_are_found() {
+        let mut round = Round::new(rules(2, 0), &world_map());
+        let now = Instant::now();
+        assert!(!round.over(now));
+        round.coins_found = 2;
+        assert!(round.over(now));
+    }
+
+    #[test]
+    fn over_when_time_is_up() {
+        let round = Round::new(GameRules { time_limit: Some(60), ..rules(3, 0) }, &world_map());
+        assert!(!round.over(round.started + Duration::from_secs(59)));
+        assert!(round.over(round.started + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn over_when_a_player_reaches_the_score_limit() {
+        let mut round = Round::new(GameRules { score_limit: Some(2), ..rules(10, 0) }, &world_map());
+        round.join(1);
+        round.points.insert(1, 1);
+        assert!(!round.over(Instant::now()));
+        round.points.insert(1, 2);
+        assert!(round.over(Instant::now()));
+    }
+
+    #[test]
+    fn the_winner_has_the_most_points() {
+        let mut round = Round::new(rules(3, 0), &world_map());
+        round.join(1);
+        round.join(2);
+        assert_eq!(round.winner(), None);
+        round.points.insert(1, 1);
+        round.points.insert(2, 2);
+        assert_eq!(round.winner(), Some(2));
+    }
+
+    #[test]
+    fn ties_go_to_whoever_got_there_first() {
+        let mut round = Round::new(rules(10, 0), &world_map());
+        round.join(1);
+        round.join(2);
+        round.join(3);
+        round.update(Instant::now(), &on_coin(3), &world_map());
+        round.update(Instant::now(), &on_coin(2), &world_map());
+        round.update(Instant::now(), &on_coin(3), &world_map());
+        round.update(Instant::now(), &on_coin(2), &world_map());
+        assert_eq!(round.winner(), Some(3));
+        round.update(Instant::now(), &on_coin(1), &world_map());
+        round.update(Instant::now(), &on_coin(1), &world_map());
+        assert_eq!(round.winner(), Some(3));
+        // without a history, the lowest id wins
+        round.scored_at.clear();
+        assert_eq!(round.winner(), Some(1));
+    }
+
+    #[test]
+    fn the_last_coin_ends_the_round_and_restarts_it() {
+        let mut round = Round::new(rules(1, 0), &world_map());
+        round.join(1);
+        let events = round.update(Instant::now(), &on_coin(1), &world_map());
+        assert_eq!(events, vec![
+            RoundEvent::CoinFound { who: 1, coins_found: 1 },
+            RoundEvent::RoundOver { winner: Some(1) },
+        ]);
+        assert_eq!(round.points[&1], 0);
+        assert_eq!(round.coins_found, 0);
+    }
+
+    #[test]
+    fn leaving_mid_round_keeps_points_until_the_round_ends() {
+        let mut round = Round::new(rules(3, 0), &world_map());
+        round.join(1);
+        round.update(Instant::now(), &on_coin(1), &world_map());
+        round.leave(1);
+        assert!(!round.points.contains_key(&1));
+        assert_eq!(round.winner(), None);
+        round.join(1);
+        assert_eq!(round.points[&1], 1);
+        round.leave(1);
+        round.restart(&world_map());
+        round.join(1);
+        assert_eq!(round.points[&1], 0);
+    }
+
+    #[test]
+    fn smaller_levels_get_fewer_coins() {
+        let big = vec![vec![1, 1, 1, 1], vec![1, 0, 0, 1], vec![1, 0, 0, 1], vec![1, 1, 1, 1]];
+        let mut round = Round::new(GameRules { simultaneous_coins: 3, ..rules(3, 0) }, &big);
+        assert_eq!(round.gold_coins.len(), 3);
+        round.restart(&world_map());
+        assert_eq!(round.gold_coins, vec![(1.5, 1.5)]);
+        round.restart(&big);
+        assert_eq!(round.gold_coins.len(), 3);
+    }
+
+    #[test]
+    fn rules_must_make_a_playable_round() {
+        assert!(rules(3, 0).validate(&world_map()).is_ok());
+        assert!(rules(0, 0).validate(&world_map()).is_err());
+        assert!(GameRules { simultaneous_coins: 0, ..rules(3, 0) }.validate(&world_map()).is_err());
+        assert!(GameRules { simultaneous_coins: 2, ..rules(3, 0) }.validate(&world_map()).is_err());
+    }
+
+    #[test]
+    fn rules_set_at_runtime_are_validated_too() {
+        let mut rules = rules(3, 0);
+        assert!(rules.set("coins_per_round", "5", &world_map()).is_ok());
+        assert_eq!(rules.coins_per_round, 5);
+        assert!(rules.set("coins_per_round", "0", &world_map()).is_err());
+        assert!(rules.set("simultaneous_coins", "0", &world_map()).is_err());
+        assert!(rules.set("simultaneous_coins", "2", &world_map()).is_err());
+        assert_eq!(rules.coins_per_round, 5);
+        assert_eq!(rules.simultaneous_coins, 1);
+    }
+}
@@ -0,0 +1,254 @@
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use crate::protocol::Door;
+
+/// Doors opened for longer than this close after it anyway
+const LONGEST_OPEN_DOOR: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Grid {
+    Map,
+    Layer,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Condition {
+    pub grid: Grid,
+    pub x: usize,
+    pub y: usize,
+    pub value: u8,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Effect {
+    Set { grid: Grid, x: usize, y: usize, value: u8 },
+    Toggle { grid: Grid, x: usize, y: usize, values: (u8, u8) },
+    OpenDoor { x: usize, y: usize, seconds: f32 },
+    ToggleDoor { x: usize, y: usize },
+    Text { text: String, seconds: u64 },
+}
+
+/// Fires its effects when `action` is performed while standing on cell (x, y)
+/// and every condition holds.
+#[derive(Deserialize, Debug)]
+pub struct Trigger {
+    pub action: u8,
+    pub x: usize,
+    pub y: usize,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    pub effects: Vec<Effect>,
+}
+
+/// A cell emptied, or a door slid open, until `closes_at`
+pub struct OpenDoor {
+    x: usize,
+    y: usize,
+    closed_value: u8,
+    closes_at: Instant,
+}
+
+fn cell<'a>(grid: Grid, x: usize, y: usize, world_map: &'a mut Vec<Vec<u8>>, world_layer: &'a mut Vec<Vec<u8>>) -> &'a mut u8 {
+    match grid {
+        Grid::Map => &mut world_map[x][y],
+        Grid::Layer => &mut world_layer[x][y],
+    }
+}
+
+fn door_mut(doors: &mut Vec<Door>, x: usize, y: usize) -> Option<&mut Door> {
+    doors.iter_mut().find(|door| door.x as usize == x && door.y as usize == y)
+}
+
+/// Slides a door open, or empties a plain cell, for `seconds`
+fn open_door(open_doors: &mut Vec<OpenDoor>, x: usize, y: usize, seconds: f32, world_map: &mut Vec<Vec<u8>>, doors: &mut Vec<Door>) {
+    // levels are checked, but a bad value must not bring the server down
+    let open_for = Duration::try_from_secs_f32(seconds).unwrap_or(Duration::from_secs(0)).min(LONGEST_OPEN_DOOR);
+    let closes_at = Instant::now() + open_for;
+    if let Some(door) = open_doors.iter_mut().find(|door| door.x == x && door.y == y) {
+        door.closes_at = closes_at;
+        if let Some(door) = door_mut(doors, x, y) {
+            door.opening = true;
+        }
+    }
+    else if let Some(door) = door_mut(doors, x, y) {
+        door.opening = true;
+        open_doors.push(OpenDoor { x: x, y: y, closed_value: world_map[x][y], closes_at: closes_at });
+    }
+    else if world_map[x][y] != 0 {
+        open_doors.push(OpenDoor { x: x, y: y, closed_value: world_map[x][y], closes_at: closes_at });
+        world_map[x][y] = 0;
+    }
+}
+
+/// Applies the triggers matching the action, returns the texts to send to the player who acted
+pub fn fire(triggers: &Vec<Trigger>, open_doors: &mut Vec<OpenDoor>, pos_x: f32, pos_y: f32, action: u8, world_map: &mut Vec<Vec<u8>>, world_layer: &mut Vec<Vec<u8>>, doors: &mut Vec<Door>) -> Vec<(String, Duration)> {
+    let mut texts = vec![];
+    for trigger in triggers {
+        if trigger.action != action || trigger.x != pos_x as usize || trigger.y != pos_y as usize {
+            continue;
+        }
+        let conditions_met = trigger.conditions.iter().all(|condition| {
+            *cell(condition.grid, condition.x, condition.y, world_map, world_layer) == condition.value
+        });
+        if !conditions_met {
+            continue;
+        }
+        for effect in &trigger.effects {
+            match effect {
+                Effect::Set { grid, x, y, value } => {
+                    *cell(*grid, *x, *y, world_map, world_layer) = *value;
+                },
+                Effect::Toggle { grid, x, y, values } => {
+                    let c = cell(*grid, *x, *y, world_map, world_layer);
+                    *c = if *c == values.0 { values.1 } else { values.0 };
+                },
+                Effect::OpenDoor { x, y, seconds } => {
+                    open_door(open_doors, *x, *y, *seconds, world_map, doors);
+                },
+                Effect::ToggleDoor { x, y } => {
+                    // a door toggled by hand stays as it is left
+                    open_doors.retain(|door| door.x != *x || door.y != *y);
+                    if let Some(door) = door_mut(doors, *x, *y) {
+                        door.opening = !door.opening;
+                    }
+                },
+                Effect::Text { text, seconds } => {
+                    texts.push((text.clone(), Duration::from_secs(*seconds)));
+                },
+            }
+        }
+    }
+    texts
+}
+
+/// Closes the doors whose time is up, but not on the `occupied` cells, returns their cells
+pub fn close_doors(open_doors: &mut Vec<OpenDoor>, world_map: &mut Vec<Vec<u8>>, doors: &mut Vec<Door>, occupied: &Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    let now = Instant::now();
+    let mut closed = vec![];
+    open_doors.retain(|door| {
+        if now >= door.closes_at && !occupied.contains(&(door.x, door.y)) {
+            match door_mut(doors, door.x, door.y) {
+                Some(sliding) => sliding.opening = false,
+                None => world_map[door.x][door.y] = door.closed_value,
+            }
+            closed.push((door.x, door.y));
+            false
+        }
+        else {
+            true
+        }
+    });
+    closed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 3 by 3 cells, walls around a free middle, with a door at (0, 1)
+    fn world() -> (Vec<Vec<u8>>, Vec<Vec<u8>>, Vec<Door>) {
+        let world_map = vec![vec![1, 2, 1], vec![1, 0, 1], vec![1, 1, 1]];
+        let world_layer = vec![vec![0; 3]; 3];
+        let doors = vec![Door { x: 0, y: 1, open: 0.0, opening: false }];
+        (world_map, world_layer, doors)
+    }
+
+    /// Fires on action 1 in the middle cell
+    fn trigger(conditions: Vec<Condition>, effects: Vec<Effect>) -> Vec<Trigger> {
+        vec![Trigger { action: 1, x: 1, y: 1, conditions: conditions, effects: effects }]
+    }
+
+    #[test]
+    fn triggers_fire_on_their_action_and_cell_only() {
+        let (mut world_map, mut world_layer, mut doors) = world();
+        let triggers = trigger(vec![], vec![Effect::Set { grid: Grid::Layer, x: 2, y: 2, value: 3 }]);
+        let mut open_doors = vec![];
+        fire(&triggers, &mut open_doors, 1.5, 1.5, 0, &mut world_map, &mut world_layer, &mut doors);
+        fire(&triggers, &mut open_doors, 2.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert_eq!(world_layer[2][2], 0);
+        fire(&triggers, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert_eq!(world_layer[2][2], 3);
+    }
+
+    #[test]
+    fn triggers_wait_for_their_conditions() {
+        let (mut world_map, mut world_layer, mut doors) = world();
+        let conditions = vec![Condition { grid: Grid::Layer, x: 0, y: 0, value: 5 }];
+        let triggers = trigger(conditions, vec![Effect::Set { grid: Grid::Map, x: 2, y: 2, value: 0 }]);
+        let mut open_doors = vec![];
+        fire(&triggers, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert_eq!(world_map[2][2], 1);
+        world_layer[0][0] = 5;
+        fire(&triggers, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert_eq!(world_map[2][2], 0);
+    }
+
+    #[test]
+    fn toggles_switch_between_their_values() {
+        let (mut world_map, mut world_layer, mut doors) = world();
+        let triggers = trigger(vec![], vec![Effect::Toggle { grid: Grid::Layer, x: 1, y: 2, values: (4, 5) }]);
+        let mut open_doors = vec![];
+        fire(&triggers, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert_eq!(world_layer[1][2], 4);
+        fire(&triggers, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert_eq!(world_layer[1][2], 5);
+        fire(&triggers, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert_eq!(world_layer[1][2], 4);
+    }
+
+    #[test]
+    fn texts_go_back_to_who_acted() {
+        let (mut world_map, mut world_layer, mut doors) = world();
+        let triggers = trigger(vec![], vec![Effect::Text { text: String::from("click"), seconds: 2 }]);
+        let texts = fire(&triggers, &mut vec![], 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert_eq!(texts, vec![(String::from("click"), Duration::from_secs(2))]);
+    }
+
+    #[test]
+    fn opened_cells_close_again_once_nobody_stands_there() {
+        let (mut world_map, mut world_layer, mut doors) = world();
+        let triggers = trigger(vec![], vec![Effect::OpenDoor { x: 2, y: 1, seconds: 0.0 }]);
+        let mut open_doors = vec![];
+        fire(&triggers, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert_eq!(world_map[2][1], 0);
+        assert!(close_doors(&mut open_doors, &mut world_map, &mut doors, &vec![(2, 1)]).is_empty());
+        assert_eq!(world_map[2][1], 0);
+        assert_eq!(close_doors(&mut open_doors, &mut world_map, &mut doors, &vec![]), vec![(2, 1)]);
+        assert_eq!(world_map[2][1], 1);
+        assert!(open_doors.is_empty());
+    }
+
+    #[test]
+    fn doors_slide_open_for_a_while() {
+        let (mut world_map, mut world_layer, mut doors) = world();
+        let triggers = trigger(vec![], vec![Effect::OpenDoor { x: 0, y: 1, seconds: 0.0 }]);
+        let mut open_doors = vec![];
+        fire(&triggers, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        // the cell keeps its texture, only the door moves
+        assert_eq!(world_map[0][1], 2);
+        assert!(doors[0].opening);
+        assert!(close_doors(&mut open_doors, &mut world_map, &mut doors, &vec![(0, 1)]).is_empty());
+        assert!(doors[0].opening);
+        assert_eq!(close_doors(&mut open_doors, &mut world_map, &mut doors, &vec![]), vec![(0, 1)]);
+        assert!(!doors[0].opening);
+        assert_eq!(world_map[0][1], 2);
+    }
+
+    #[test]
+    fn toggled_doors_stay_as_they_are_left() {
+        let (mut world_map, mut world_layer, mut doors) = world();
+        let open = trigger(vec![], vec![Effect::OpenDoor { x: 0, y: 1, seconds: 0.0 }]);
+        let toggle = trigger(vec![], vec![Effect::ToggleDoor { x: 0, y: 1 }]);
+        let mut open_doors = vec![];
+        fire(&open, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        fire(&toggle, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert!(!doors[0].opening);
+        fire(&toggle, &mut open_doors, 1.5, 1.5, 1, &mut world_map, &mut world_layer, &mut doors);
+        assert!(doors[0].opening);
+        // no longer timed, nothing closes it
+        assert!(close_doors(&mut open_doors, &mut world_map, &mut doors, &vec![]).is_empty());
+        assert!(doors[0].opening);
+    }
+}
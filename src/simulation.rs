@@ -0,0 +1,178 @@
+use crate::protocol::{Door, InputCommand, Movement, Position};
+use std::time::Duration;
+
+pub const MOVE_SPEED: f32 = 0.1;
+pub const ROT_SPEED: f32 = 0.1;
+/// length of the camera plane, which sets the field of view
+pub const PLANE_LENGTH: f32 = 0.66;
+/// How close to a portal a player must be to take it
+pub const PORTAL_REACH: f32 = 2.0;
+/// How long a door takes to slide from shut to open, in seconds
+pub const DOOR_SLIDE_TIME: f32 = 1.0;
+
+/// Player position, direction and camera plane, as simulated by both client and server
+#[derive(Debug, Clone, Copy)]
+pub struct Camera {
+    pub pos_x: f32,
+    pub pos_y: f32,
+    pub dir_x: f32,
+    pub dir_y: f32,
+    pub plane_x: f32,
+    pub plane_y: f32,
+}
+
+impl Camera {
+    /// The camera plane is always perpendicular to the direction
+    pub fn from_position(position: &Position) -> Camera {
+        Camera {
+            pos_x: position.x,
+            pos_y: position.y,
+            dir_x: position.dir_x,
+            dir_y: position.dir_y,
+            plane_x: position.dir_y * PLANE_LENGTH,
+            plane_y: -position.dir_x * PLANE_LENGTH,
+        }
+    }
+
+    pub fn to_position(&self, speed: f32) -> Position {
+        Position { x: self.pos_x, y: self.pos_y, dir_x: self.dir_x, dir_y: self.dir_y, speed: speed }
+    }
+
+    fn rotate(&mut self, angle: f32) {
+        let old_dir_x = self.dir_x;
+        self.dir_x = self.dir_x * angle.cos() - self.dir_y * angle.sin();
+        self.dir_y = old_dir_x * angle.sin() + self.dir_y * angle.cos();
+        let old_plane_x = self.plane_x;
+        self.plane_x = self.plane_x * angle.cos() - self.plane_y * angle.sin();
+        self.plane_y = old_plane_x * angle.sin() + self.plane_y * angle.cos();
+    }
+
+    fn step(&mut self, world_map: &Vec<Vec<u8>>, doors: &Vec<Door>, step_x: f32, step_y: f32) {
+        let (x, y) = slide(world_map, doors, self.pos_x, self.pos_y, step_x, step_y);
+        self.pos_x = x;
+        self.pos_y = y;
+    }
+}
+
+/// Moves (x, y) along (step_x, step_y), each axis separately so that players slide along walls
+pub fn slide(world_map: &Vec<Vec<u8>>, doors: &Vec<Door>, x: f32, y: f32, step_x: f32, step_y: f32) -> (f32, f32) {
+    let x = if is_free(world_map, doors, x + step_x, y) { x + step_x } else { x };
+    let y = if is_free(world_map, doors, x, y + step_y) { y + step_y } else { y };
+    (x, y)
+}
+
+/// Whether (x, y) is inside the map and in an empty cell, or in the gap a door leaves as it slides open
+pub fn is_free(world_map: &Vec<Vec<u8>>, doors: &Vec<Door>, x: f32, y: f32) -> bool {
+    if x < 0.0 || y < 0.0 {
+        return false;
+    }
+    let (cell_x, cell_y) = (x as usize, y as usize);
+    if cell_x >= world_map.len() || cell_y >= world_map[cell_x].len() {
+        return false;
+    }
+    if world_map[cell_x][cell_y] == 0 {
+        return true;
+    }
+    match door_at(doors, cell_x, cell_y) {
+        Some(door) => {
+            let along = if door_faces_x(world_map, door) { y } else { x };
+            along - along.floor() < door.open
+        },
+        None => false,
+    }
+}
+
+/// The door in cell (x, y), if any
+pub fn door_at(doors: &Vec<Door>, x: usize, y: usize) -> Option<&Door> {
+    doors.iter().find(|door| door.x as usize == x && door.y as usize == y)
+}
+
+/// Whether `door` is set in a wall running along y, so that it is crossed walking along x,
+/// stands at the middle of its cell across x, and slides along y
+pub fn door_faces_x(world_map: &Vec<Vec<u8>>, door: &Door) -> bool {
+    let (x, y) = (door.x as usize, door.y as usize);
+    let wall = |y: usize| world_map.get(x).and_then(|column| column.get(y)).map_or(true, |cell| *cell != 0);
+    wall(y.wrapping_sub(1)) && wall(y + 1)
+}
+
+/// Moves the doors along for `elapsed`
+pub fn slide_doors(doors: &mut Vec<Door>, elapsed: Duration) {
+    let step = elapsed.as_secs_f32() / DOOR_SLIDE_TIME;
+    for door in doors.iter_mut() {
+        door.open = if door.opening { (door.open + step).min(1.0) } else { (door.open - step).max(0.0) };
+    }
+}
+
+/// Turns the camera for a rotation, returns the step a walking movement makes and the resulting speed
+fn steer(movement: Movement, camera: &mut Camera) -> (f32, f32, f32) {
+    // direction rotated by a quarter turn, used for strafing
+    let side_x = -camera.dir_y;
+    let side_y = camera.dir_x;
+    match movement {
+        Movement::WalkForward => (camera.dir_x * MOVE_SPEED, camera.dir_y * MOVE_SPEED, MOVE_SPEED),
+        Movement::WalkBackward => (-camera.dir_x * MOVE_SPEED, -camera.dir_y * MOVE_SPEED, -MOVE_SPEED),
+        Movement::StrafeRight => (-side_x * MOVE_SPEED, -side_y * MOVE_SPEED, -MOVE_SPEED),
+        Movement::StrafeLeft => (side_x * MOVE_SPEED, side_y * MOVE_SPEED, -MOVE_SPEED),
+        Movement::RotateRight => {
+            camera.rotate(-ROT_SPEED);
+            (0.0, 0.0, 0.0)
+        },
+        Movement::RotateLeft => {
+            camera.rotate(ROT_SPEED);
+            (0.0, 0.0, 0.0)
+        },
+    }
+}
+
+/// Applies one frame of movement, returns the resulting speed
+pub fn apply(movement: Movement, world_map: &Vec<Vec<u8>>, doors: &Vec<Door>, camera: &mut Camera) -> f32 {
+    let (step_x, step_y, speed) = steer(movement, camera);
+    if step_x != 0.0 || step_y != 0.0 {
+        camera.step(world_map, doors, step_x, step_y);
+    }
+    speed
+}
+
+/// Applies one frame of movement to a spectator camera, which goes through walls but stays on the map
+pub fn fly(movement: Movement, world_map: &Vec<Vec<u8>>, camera: &mut Camera) {
+    let (step_x, step_y, _) = steer(movement, camera);
+    let width = world_map.len() as f32;
+    let height = world_map.first().map_or(0, |column| column.len()) as f32;
+    camera.pos_x = (camera.pos_x + step_x).max(0.0).min(width - MOVE_SPEED);
+    camera.pos_y = (camera.pos_y + step_y).max(0.0).min(height - MOVE_SPEED);
+}
+
+/// Moves the camera to the destination of portal `index` if it is close enough to it
+pub fn take_portal(index: usize, portals: &Vec<Vec<f32>>, portals_destinations: &Vec<Vec<f32>>, camera: &mut Camera) -> bool {
+    if index >= portals.len() || index >= portals_destinations.len() {
+        return false;
+    }
+    let dx = camera.pos_x - portals[index][0];
+    let dy = camera.pos_y - portals[index][1];
+    if (dx * dx + dy * dy).sqrt() >= PORTAL_REACH {
+        return false;
+    }
+    camera.pos_x = portals_destinations[index][0];
+    camera.pos_y = portals_destinations[index][1];
+    true
+}
+
+/// Applies one input command, returns the resulting speed
+pub fn run(command: InputCommand, world_map: &Vec<Vec<u8>>, doors: &Vec<Door>, portals: &Vec<Vec<f32>>, portals_destinations: &Vec<Vec<f32>>, camera: &mut Camera) -> f32 {
+    match command {
+        InputCommand::Move(movement) => apply(movement, world_map, doors, camera),
+        InputCommand::TakePortal(index) => {
+            take_portal(index as usize, portals, portals_destinations, camera);
+            0.0
+        },
+    }
+}
+
+/// Starts from a position sent by the server and replays the commands it has not seen yet
+pub fn replay<'a>(position: &Position, commands: impl Iterator<Item = &'a InputCommand>, world_map: &Vec<Vec<u8>>, doors: &Vec<Door>, portals: &Vec<Vec<f32>>, portals_destinations: &Vec<Vec<f32>>) -> Camera {
+    let mut camera = Camera::from_position(position);
+    for command in commands {
+        run(*command, world_map, doors, portals, portals_destinations, &mut camera);
+    }
+    camera
+}